@@ -0,0 +1,198 @@
+//! Interactive chat simulator: type messages as a chosen user in a group,
+//! room or DM and see what the dolphin answers.
+//!
+//! Messages go through the same webhook event handling as the deployed bot,
+//! with an in-memory state store (`enabled`, `msg_history:*`, ...). Broadcast
+//! admins and other settings are read from the environment / `.env`.
+//!
+//! ```text
+//! dolphin-cli [--user U123] [--group C456 | --room R789]
+//! ```
+
+use line_dolphin_worker::dolphin::Dolphin;
+use line_dolphin_worker::line::{LineCall, RecordingLineClient};
+use line_dolphin_worker::server::ProcessEnv;
+use line_dolphin_worker::state::MemoryStore;
+use line_dolphin_worker::types::{ReplyMessage, WebhookEvent};
+use serde_json::json;
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const HELP: &str = "\
+Commands:
+  /user <id>    speak as another user
+  /group <id>   move to a group chat
+  /room <id>    move to a multi-person room
+  /dm           move to a one-on-one chat with the bot
+  /help         show this help
+  /quit         exit
+Anything else is sent as a text message.";
+
+enum Conversation {
+    Dm,
+    Group(String),
+    Room(String),
+}
+
+struct Session {
+    user_id: String,
+    conversation: Conversation,
+    /// Numbers the event and message IDs; timestamps are the current time.
+    next_id: u64,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl Session {
+    fn prompt(&self) -> String {
+        match &self.conversation {
+            Conversation::Dm => format!("[dm {}]> ", self.user_id),
+            Conversation::Group(id) => format!("[group {} {}]> ", id, self.user_id),
+            Conversation::Room(id) => format!("[room {} {}]> ", id, self.user_id),
+        }
+    }
+
+    /// Builds a text message webhook event the way LINE would send it.
+    fn text_event(&mut self, text: &str) -> WebhookEvent {
+        self.next_id += 1;
+        let source = match &self.conversation {
+            Conversation::Dm => json!({ "type": "user", "userId": self.user_id }),
+            Conversation::Group(id) => {
+                json!({ "type": "group", "groupId": id, "userId": self.user_id })
+            }
+            Conversation::Room(id) => {
+                json!({ "type": "room", "roomId": id, "userId": self.user_id })
+            }
+        };
+        let event = json!({
+            "type": "message",
+            "message": {
+                "type": "text",
+                "id": self.next_id.to_string(),
+                "quoteToken": format!("cli-quote-{}", self.next_id),
+                "text": text,
+            },
+            "webhookEventId": format!("cli-event-{}", self.next_id),
+            "deliveryContext": { "isRedelivery": false },
+            "timestamp": now_millis(),
+            "source": source,
+            "replyToken": format!("cli-reply-{}", self.next_id),
+            "mode": "active",
+        });
+        serde_json::from_value(event).expect("CLI builds valid webhook events")
+    }
+}
+
+fn print_messages(prefix: &str, messages: &[ReplyMessage]) {
+    for message in messages {
+        match message {
+            ReplyMessage::Text { text, .. } | ReplyMessage::TextV2 { text, .. } => {
+                println!("{}{}", prefix, text)
+            }
+            ReplyMessage::Sticker {
+                package_id,
+                sticker_id,
+                ..
+            } => println!("{}[sticker {}/{}]", prefix, package_id, sticker_id),
+            ReplyMessage::Flex { alt_text, .. } => println!("{}[flex] {}", prefix, alt_text),
+        }
+    }
+}
+
+fn parse_args() -> Session {
+    let mut session = Session {
+        user_id: "U00000000000000000000000000000001".to_string(),
+        conversation: Conversation::Dm,
+        next_id: 0,
+    };
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().unwrap_or_else(|| {
+                eprintln!("{} needs a value", arg);
+                std::process::exit(2);
+            })
+        };
+        match arg.as_str() {
+            "--user" => session.user_id = value(),
+            "--group" => session.conversation = Conversation::Group(value()),
+            "--room" => session.conversation = Conversation::Room(value()),
+            _ => {
+                eprintln!("usage: dolphin-cli [--user ID] [--group ID | --room ID]");
+                std::process::exit(2);
+            }
+        }
+    }
+
+    session
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    dotenv::dotenv().ok();
+
+    let mut session = parse_args();
+    let env = ProcessEnv;
+    let store = MemoryStore::new();
+    let line = RecordingLineClient::new();
+    let dolphin = Dolphin::new(&env, &store, &line);
+
+    println!("🐬 Dolphin chat simulator. Type /help for commands.");
+
+    let stdin = io::stdin();
+    loop {
+        print!("{}", session.prompt());
+        io::stdout().flush().ok();
+
+        let mut input = String::new();
+        if stdin.lock().read_line(&mut input).unwrap_or(0) == 0 {
+            println!();
+            break;
+        }
+        let input = input.trim_end_matches(['\r', '\n']);
+
+        let mut words = input.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("/quit"), _) => break,
+            (Some("/help"), _) => println!("{}", HELP),
+            (Some("/dm"), _) => session.conversation = Conversation::Dm,
+            (Some("/user"), Some(id)) => session.user_id = id.to_string(),
+            (Some("/group"), Some(id)) => {
+                session.conversation = Conversation::Group(id.to_string())
+            }
+            (Some("/room"), Some(id)) => session.conversation = Conversation::Room(id.to_string()),
+            (Some(command), _) if command.starts_with('/') => println!("{}", HELP),
+            (None, _) => {}
+            _ => {
+                let event = session.text_event(input);
+                dolphin.handle_event(&event).await;
+
+                let calls = line.take_calls();
+                if calls.is_empty() {
+                    println!("   (the dolphin stays silent)");
+                }
+                for call in calls {
+                    match call {
+                        LineCall::Reply { messages, .. } => print_messages("🐬 ", &messages),
+                        LineCall::Push { to, messages } => {
+                            print_messages(&format!("🐬 push to {}: ", to), &messages)
+                        }
+                        LineCall::AcquireChatControl { chat_id } => {
+                            println!("   (takes over chat {})", chat_id)
+                        }
+                        LineCall::ReleaseChatControl { chat_id } => {
+                            println!("   (hands back chat {})", chat_id)
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
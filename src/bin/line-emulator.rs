@@ -0,0 +1,474 @@
+//! A local stand-in for `api.line.me`.
+//!
+//! Accepts reply and push calls from the bot, checks the bearer token and
+//! reply token rules, and keeps everything it receives for inspection. It can
+//! also sign and send synthetic webhooks to the bot. Point the bot at it with
+//! `LINE_API_BASE_URL=http://localhost:8090`.
+//!
+//! Endpoints:
+//! - `POST /v2/bot/message/reply`, `POST /v2/bot/message/push`: the Messaging API
+//! - `POST /v2/bot/chat/{chatId}/control/acquire` and `.../release`: Chat Control,
+//!   recorded with the chat ID as `to` and no messages
+//! - `GET /v2/bot/profile/{userId}` and the group and room member profiles:
+//!   every user is called "User" and the last 4 characters of their ID
+//! - `GET /emulator/messages`: everything received so far
+//! - `DELETE /emulator/messages`: forget received messages
+//! - `POST /emulator/webhook`: send a webhook to the bot, either
+//!   `{"text": "...", "userId": "...", "groupId": "...", "roomId": "..."}`
+//!   or a raw `{"events": [...]}` batch
+
+use axum::{
+    extract::{Json, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Router,
+};
+use line_dolphin_worker::dolphin::sign_body;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+struct Config {
+    channel_access_token: String,
+    channel_secret: String,
+    bot_webhook_url: String,
+    reply_token_ttl: Duration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ReceivedMessage {
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none", rename = "replyToken")]
+    reply_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<String>,
+    messages: Vec<Value>,
+    timestamp: u64,
+}
+
+#[derive(Default)]
+struct Emulator {
+    /// Issued reply tokens that haven't been used yet.
+    reply_tokens: HashMap<String, Instant>,
+    received: Vec<ReceivedMessage>,
+    next_id: u64,
+}
+
+struct AppState {
+    config: Config,
+    emulator: Mutex<Emulator>,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplyBody {
+    #[serde(rename = "replyToken")]
+    reply_token: String,
+    messages: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PushBody {
+    to: String,
+    messages: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SyntheticWebhook {
+    text: Option<String>,
+    #[serde(rename = "userId")]
+    user_id: Option<String>,
+    #[serde(rename = "groupId")]
+    group_id: Option<String>,
+    #[serde(rename = "roomId")]
+    room_id: Option<String>,
+    events: Option<Vec<Value>>,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn api_error(status: StatusCode, message: &str) -> (StatusCode, Json<Value>) {
+    (status, Json(json!({ "message": message })))
+}
+
+fn check_bearer(state: &AppState, headers: &HeaderMap) -> Result<(), (StatusCode, Json<Value>)> {
+    let expected = format!("Bearer {}", state.config.channel_access_token);
+    match headers.get("authorization").and_then(|h| h.to_str().ok()) {
+        Some(value) if value == expected => Ok(()),
+        _ => {
+            warn!("Rejected call with a missing or wrong channel access token");
+            Err(api_error(
+                StatusCode::UNAUTHORIZED,
+                "Authentication failed. Confirm that the access token in the authorization header is valid.",
+            ))
+        }
+    }
+}
+
+fn check_messages(messages: &[Value]) -> Result<(), (StatusCode, Json<Value>)> {
+    if messages.is_empty() || messages.len() > 5 {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "The request body has 1 error(s): messages must contain 1 to 5 items",
+        ));
+    }
+    for (index, message) in messages.iter().enumerate() {
+        let alt_text = message.get("altText").and_then(Value::as_str);
+        if message["type"] == "flex" && alt_text.is_none_or(str::is_empty) {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                &format!(
+                    "The request body has 1 error(s): messages[{}].altText may not be empty",
+                    index
+                ),
+            ));
+        }
+        let text = message.get("text").and_then(Value::as_str);
+        if text.is_some_and(|text| text.encode_utf16().count() > 5000) {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                &format!(
+                    "The request body has 1 error(s): messages[{}].text must be at most 5000 characters",
+                    index
+                ),
+            ));
+        }
+        let items = message["quickReply"]["items"].as_array();
+        if items.is_some_and(|items| items.is_empty() || items.len() > 13) {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                &format!(
+                    "The request body has 1 error(s): messages[{}].quickReply.items must contain 1 to 13 items",
+                    index
+                ),
+            ));
+        }
+        let labels = items
+            .into_iter()
+            .flatten()
+            .map(|item| &item["action"]["label"]);
+        if labels
+            .filter_map(Value::as_str)
+            .any(|label| label.chars().count() > 20)
+        {
+            return Err(api_error(
+                StatusCode::BAD_REQUEST,
+                &format!(
+                    "The request body has 1 error(s): messages[{}].quickReply.items.action.label must be at most 20 characters",
+                    index
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+async fn reply_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<ReplyBody>,
+) -> impl IntoResponse {
+    if let Err(e) = check_bearer(&state, &headers) {
+        return e;
+    }
+    if let Err(e) = check_messages(&body.messages) {
+        return e;
+    }
+
+    let mut emulator = state.emulator.lock().unwrap();
+    // Reply tokens are single use: remove it whether or not it's still valid
+    match emulator.reply_tokens.remove(&body.reply_token) {
+        Some(issued_at) if issued_at.elapsed() <= state.config.reply_token_ttl => {}
+        Some(_) => {
+            warn!("Expired reply token: {}", body.reply_token);
+            return api_error(StatusCode::BAD_REQUEST, "Invalid reply token");
+        }
+        None => {
+            warn!("Unknown or reused reply token: {}", body.reply_token);
+            return api_error(StatusCode::BAD_REQUEST, "Invalid reply token");
+        }
+    }
+
+    info!("Reply {}: {:?}", body.reply_token, body.messages);
+    emulator.received.push(ReceivedMessage {
+        kind: "reply",
+        reply_token: Some(body.reply_token),
+        to: None,
+        messages: body.messages,
+        timestamp: now_millis(),
+    });
+
+    (StatusCode::OK, Json(json!({})))
+}
+
+async fn push_handler(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Json(body): Json<PushBody>,
+) -> impl IntoResponse {
+    if let Err(e) = check_bearer(&state, &headers) {
+        return e;
+    }
+    if let Err(e) = check_messages(&body.messages) {
+        return e;
+    }
+
+    info!("Push to {}: {:?}", body.to, body.messages);
+    state
+        .emulator
+        .lock()
+        .unwrap()
+        .received
+        .push(ReceivedMessage {
+            kind: "push",
+            reply_token: None,
+            to: Some(body.to),
+            messages: body.messages,
+            timestamp: now_millis(),
+        });
+
+    (StatusCode::OK, Json(json!({})))
+}
+
+fn record_chat_control(
+    state: &AppState,
+    headers: &HeaderMap,
+    kind: &'static str,
+    chat_id: String,
+) -> (StatusCode, Json<Value>) {
+    if let Err(e) = check_bearer(state, headers) {
+        return e;
+    }
+
+    info!("Chat control {} for {}", kind, chat_id);
+    state
+        .emulator
+        .lock()
+        .unwrap()
+        .received
+        .push(ReceivedMessage {
+            kind,
+            reply_token: None,
+            to: Some(chat_id),
+            messages: Vec::new(),
+            timestamp: now_millis(),
+        });
+
+    (StatusCode::OK, Json(json!({})))
+}
+
+async fn acquire_handler(
+    State(state): State<Arc<AppState>>,
+    Path(chat_id): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    record_chat_control(&state, &headers, "acquireChatControl", chat_id)
+}
+
+async fn release_handler(
+    State(state): State<Arc<AppState>>,
+    Path(chat_id): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    record_chat_control(&state, &headers, "releaseChatControl", chat_id)
+}
+
+fn profile(state: &AppState, headers: &HeaderMap, user_id: String) -> (StatusCode, Json<Value>) {
+    if let Err(e) = check_bearer(state, headers) {
+        return e;
+    }
+    let suffix = user_id
+        .get(user_id.len().saturating_sub(4)..)
+        .unwrap_or(&user_id);
+    let display_name = format!("User {}", suffix);
+    (
+        StatusCode::OK,
+        Json(json!({"displayName": display_name, "userId": user_id})),
+    )
+}
+
+async fn profile_handler(
+    State(state): State<Arc<AppState>>,
+    Path(user_id): Path<String>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    profile(&state, &headers, user_id)
+}
+
+async fn member_profile_handler(
+    State(state): State<Arc<AppState>>,
+    Path((_chat_id, user_id)): Path<(String, String)>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    profile(&state, &headers, user_id)
+}
+
+async fn list_messages(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(state.emulator.lock().unwrap().received.clone())
+}
+
+async fn clear_messages(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    state.emulator.lock().unwrap().received.clear();
+    StatusCode::NO_CONTENT
+}
+
+/// Builds a text message event with a fresh reply token.
+fn text_event(emulator: &mut Emulator, request: &SyntheticWebhook) -> Value {
+    emulator.next_id += 1;
+    let id = emulator.next_id;
+    let reply_token = format!("emulator-reply-{}", id);
+    emulator
+        .reply_tokens
+        .insert(reply_token.clone(), Instant::now());
+
+    let user_id = request
+        .user_id
+        .clone()
+        .unwrap_or_else(|| "U00000000000000000000000000000001".to_string());
+    let source = if let Some(group_id) = &request.group_id {
+        json!({ "type": "group", "groupId": group_id, "userId": user_id })
+    } else if let Some(room_id) = &request.room_id {
+        json!({ "type": "room", "roomId": room_id, "userId": user_id })
+    } else {
+        json!({ "type": "user", "userId": user_id })
+    };
+
+    json!({
+        "type": "message",
+        "message": {
+            "type": "text",
+            "id": id.to_string(),
+            "quoteToken": format!("emulator-quote-{}", id),
+            "text": request.text.clone().unwrap_or_default(),
+        },
+        "webhookEventId": format!("emulator-event-{}", id),
+        "deliveryContext": { "isRedelivery": false },
+        "timestamp": now_millis(),
+        "source": source,
+        "replyToken": reply_token,
+        "mode": "active",
+    })
+}
+
+async fn send_webhook(
+    State(state): State<Arc<AppState>>,
+    Json(request): Json<SyntheticWebhook>,
+) -> impl IntoResponse {
+    let events = {
+        let mut emulator = state.emulator.lock().unwrap();
+        match &request.events {
+            Some(events) => {
+                // Honour reply tokens of raw events so the bot can answer them
+                for token in events.iter().filter_map(|e| e["replyToken"].as_str()) {
+                    emulator
+                        .reply_tokens
+                        .insert(token.to_string(), Instant::now());
+                }
+                events.clone()
+            }
+            None => vec![text_event(&mut emulator, &request)],
+        }
+    };
+
+    let body = json!({ "destination": "Uemulator", "events": events }).to_string();
+    let signature = sign_body(body.as_bytes(), &state.config.channel_secret);
+
+    let response = state
+        .client
+        .post(&state.config.bot_webhook_url)
+        .header("Content-Type", "application/json")
+        .header("x-line-signature", signature)
+        .body(body)
+        .send()
+        .await;
+
+    match response {
+        Ok(response) => {
+            let status = response.status().as_u16();
+            info!("Bot answered webhook with {}", status);
+            (
+                StatusCode::OK,
+                Json(json!({ "status": status, "events": events })),
+            )
+        }
+        Err(e) => {
+            error!("Failed to deliver webhook: {}", e);
+            api_error(StatusCode::BAD_GATEWAY, &e.to_string())
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    dotenv::dotenv().ok();
+    tracing_subscriber::fmt::init();
+
+    let config = Config {
+        channel_access_token: env::var("LINE_CHANNEL_ACCESS_TOKEN")
+            .unwrap_or_else(|_| "emulator-token".to_string()),
+        channel_secret: env::var("LINE_CHANNEL_SECRET")
+            .unwrap_or_else(|_| "emulator-secret".to_string()),
+        bot_webhook_url: env::var("BOT_WEBHOOK_URL")
+            .unwrap_or_else(|_| "http://localhost:3000/webhook".to_string()),
+        reply_token_ttl: Duration::from_secs(
+            env::var("REPLY_TOKEN_TTL_SECS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(60),
+        ),
+    };
+
+    let port = env::var("EMULATOR_PORT").unwrap_or_else(|_| "8090".to_string());
+    let addr = format!("0.0.0.0:{}", port);
+
+    info!(
+        "LINE API emulator on {}, sending webhooks to {}",
+        addr, config.bot_webhook_url
+    );
+
+    let state = Arc::new(AppState {
+        config,
+        emulator: Mutex::new(Emulator::default()),
+        client: reqwest::Client::new(),
+    });
+
+    let app = Router::new()
+        .route("/v2/bot/message/reply", post(reply_handler))
+        .route("/v2/bot/message/push", post(push_handler))
+        .route(
+            "/v2/bot/chat/{chat_id}/control/acquire",
+            post(acquire_handler),
+        )
+        .route(
+            "/v2/bot/chat/{chat_id}/control/release",
+            post(release_handler),
+        )
+        .route("/v2/bot/profile/{user_id}", get(profile_handler))
+        .route(
+            "/v2/bot/group/{group_id}/member/{user_id}",
+            get(member_profile_handler),
+        )
+        .route(
+            "/v2/bot/room/{room_id}/member/{user_id}",
+            get(member_profile_handler),
+        )
+        .route(
+            "/emulator/messages",
+            get(list_messages).delete(clear_messages),
+        )
+        .route("/emulator/webhook", post(send_webhook))
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(&addr).await.unwrap();
+    axum::serve(listener, app).await.unwrap();
+}
@@ -0,0 +1,316 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+use crate::dolphin::{Secrets, WebhookError};
+use crate::state::StateStore;
+use crate::Result;
+
+/// A LINE Official Account served by this deployment.
+///
+/// The default channel has an empty name and uses the plain configuration
+/// and state keys, so a single-account deployment needs no channel setup.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Channel {
+    pub name: String,
+    /// The bot user ID LINE sends as the webhook's `destination`.
+    pub destination: Option<String>,
+}
+
+impl Channel {
+    fn parse(entry: &str) -> Option<Self> {
+        let (name, destination) = match entry.split_once('=') {
+            Some((name, destination)) => (name.trim(), Some(destination.trim().to_string())),
+            None => (entry.trim(), None),
+        };
+        if name.is_empty() {
+            return None;
+        }
+        Some(Channel {
+            name: name.to_string(),
+            destination: destination.filter(|destination| !destination.is_empty()),
+        })
+    }
+
+    /// The channel's view of the configuration, see [`ChannelEnv`].
+    pub fn env<'e, E: Secrets>(&self, env: &'e E) -> ChannelEnv<'e, E> {
+        ChannelEnv {
+            env,
+            prefix: self.env_prefix(),
+        }
+    }
+
+    fn env_prefix(&self) -> String {
+        match self.name.as_str() {
+            "" => String::new(),
+            name => format!("{}_", name.to_uppercase().replace('-', "_")),
+        }
+    }
+
+    /// Checks that the channel has its own LINE credentials, naming the
+    /// variable that is missing.
+    pub fn check_credentials(&self, env: &impl Secrets) -> Result<()> {
+        for key in ["LINE_CHANNEL_SECRET", "LINE_CHANNEL_ACCESS_TOKEN"] {
+            if self.env(env).get(key).is_none() {
+                return Err(format!(
+                    "{}{} is required for channel '{}'",
+                    self.env_prefix(),
+                    key,
+                    self.name
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The channel's own namespace in a state store, see [`ChannelStore`].
+    pub fn store<'s, S: StateStore>(&self, store: &'s S) -> ChannelStore<'s, S> {
+        let prefix = match self.name.as_str() {
+            "" => String::new(),
+            name => format!("{}:", name),
+        };
+        ChannelStore { store, prefix }
+    }
+}
+
+/// The channels listed in `DOLPHIN_CHANNELS`, comma-separated as `<name>` or
+/// `<name>=<destination bot user ID>`.
+#[derive(Debug, Default)]
+pub struct Channels {
+    channels: Vec<Channel>,
+}
+
+impl Channels {
+    pub fn from_env(env: &impl Secrets) -> Self {
+        let channels = env
+            .get("DOLPHIN_CHANNELS")
+            .map(|var| var.split(',').filter_map(Channel::parse).collect())
+            .unwrap_or_default();
+        Channels { channels }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.iter()
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&Channel> {
+        self.channels.iter().find(|channel| channel.name == name)
+    }
+
+    pub fn by_destination(&self, destination: &str) -> Option<&Channel> {
+        self.channels
+            .iter()
+            .find(|channel| channel.destination.as_deref() == Some(destination))
+    }
+
+    /// Picks the channel a webhook request is for: the one named in the
+    /// `/webhook/{channel}` path, otherwise the one whose bot user ID is the
+    /// body's `destination`, otherwise the default channel.
+    ///
+    /// The body is not verified yet; the chosen channel's secret does that.
+    pub fn route(
+        &self,
+        path_channel: Option<&str>,
+        body: &[u8],
+    ) -> std::result::Result<Channel, WebhookError> {
+        if let Some(name) = path_channel {
+            return self
+                .by_name(name)
+                .cloned()
+                .ok_or(WebhookError::UnknownChannel);
+        }
+
+        #[derive(Deserialize)]
+        struct Destination {
+            destination: String,
+        }
+
+        let destination = serde_json::from_slice::<Destination>(body).ok();
+        Ok(destination
+            .and_then(|d| self.by_destination(&d.destination).cloned())
+            .unwrap_or_default())
+    }
+}
+
+/// Configuration for one channel: `<NAME>_<KEY>` (e.g. `SHOP_LINE_CHANNEL_SECRET`
+/// for channel `shop`) wins over the shared `<KEY>`. The account's own
+/// settings, see [`is_account_key`], have no shared fallback.
+pub struct ChannelEnv<'e, E> {
+    env: &'e E,
+    prefix: String,
+}
+
+impl<E: Secrets> Secrets for ChannelEnv<'_, E> {
+    fn get(&self, key: &str) -> Option<String> {
+        if !self.prefix.is_empty() {
+            if let Some(value) = self.env.get(&format!("{}{}", self.prefix, key)) {
+                return Some(value);
+            }
+            if is_account_key(key) {
+                return None;
+            }
+        }
+        self.env.get(key)
+    }
+}
+
+/// Settings that belong to a single LINE account: its credentials and its
+/// broadcast admins. A named channel borrowing the shared ones would answer
+/// as the default bot and let its admins push into the default bot's groups.
+fn is_account_key(key: &str) -> bool {
+    matches!(key, "LINE_CHANNEL_SECRET" | "LINE_CHANNEL_ACCESS_TOKEN")
+        || key.starts_with("DOLPHIN_USER_TO_GROUP")
+}
+
+/// A state store seen through one channel, with every key prefixed by
+/// `<name>:` so channels never share state.
+pub struct ChannelStore<'s, S> {
+    store: &'s S,
+    prefix: String,
+}
+
+impl<S> ChannelStore<'_, S> {
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+impl<S: StateStore> StateStore for ChannelStore<'_, S> {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        self.store.get(&self.key(key)).await
+    }
+
+    async fn put(&self, key: &str, value: &str) -> Result<()> {
+        self.store.put(&self.key(key), value).await
+    }
+
+    async fn put_with_ttl(&self, key: &str, value: &str, ttl: Duration) -> Result<()> {
+        self.store.put_with_ttl(&self.key(key), value, ttl).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.store.delete(&self.key(key)).await
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let keys = self.store.keys(&self.key(prefix)).await?;
+        Ok(keys
+            .into_iter()
+            .map(|key| key[self.prefix.len()..].to_string())
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::MemoryStore;
+    use std::collections::HashMap;
+
+    fn env() -> HashMap<String, String> {
+        HashMap::from([
+            (
+                "DOLPHIN_CHANNELS".to_string(),
+                "shop=Ushop, fan-club ,=Unamed".to_string(),
+            ),
+            ("LINE_CHANNEL_SECRET".to_string(), "shared".to_string()),
+            ("SHOP_LINE_CHANNEL_SECRET".to_string(), "shop".to_string()),
+            (
+                "SHOP_LINE_CHANNEL_ACCESS_TOKEN".to_string(),
+                "shop".to_string(),
+            ),
+            ("DOLPHIN_USER_TO_GROUP1".to_string(), "U1:C1".to_string()),
+            ("DOLPHIN_WELCOME_MESSAGE".to_string(), "Hi!".to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_routes_by_path_then_destination() {
+        let channels = Channels::from_env(&env());
+        let body = br#"{"destination": "Ushop", "events": []}"#;
+
+        assert_eq!(channels.iter().count(), 2);
+        assert_eq!(
+            channels.route(Some("fan-club"), body).unwrap().name,
+            "fan-club"
+        );
+        assert_eq!(channels.route(None, body).unwrap().name, "shop");
+        assert_eq!(
+            channels.route(None, br#"{"destination": "Uother"}"#),
+            Ok(Channel::default())
+        );
+        assert_eq!(
+            channels.route(Some("other"), body),
+            Err(WebhookError::UnknownChannel)
+        );
+    }
+
+    #[test]
+    fn test_channel_settings_override_shared_ones() {
+        let env = env();
+        let channels = Channels::from_env(&env);
+        let get = |channel: &Channel, key| channel.env(&env).get(key);
+        let (shop, fan_club) = (
+            channels.by_name("shop").unwrap(),
+            channels.by_name("fan-club").unwrap(),
+        );
+
+        assert_eq!(get(shop, "LINE_CHANNEL_SECRET"), Some("shop".into()));
+        assert_eq!(get(fan_club, "DOLPHIN_WELCOME_MESSAGE"), Some("Hi!".into()));
+        assert_eq!(
+            get(&Channel::default(), "LINE_CHANNEL_SECRET"),
+            Some("shared".into())
+        );
+    }
+
+    #[test]
+    fn test_named_channels_need_their_own_credentials() {
+        let env = env();
+        let channels = Channels::from_env(&env);
+        let fan_club = channels.by_name("fan-club").unwrap();
+
+        assert_eq!(fan_club.env(&env).get("LINE_CHANNEL_SECRET"), None);
+        assert_eq!(fan_club.env(&env).get("DOLPHIN_USER_TO_GROUP1"), None);
+        assert_eq!(
+            fan_club.check_credentials(&env).unwrap_err().to_string(),
+            "FAN_CLUB_LINE_CHANNEL_SECRET is required for channel 'fan-club'"
+        );
+        assert!(channels
+            .by_name("shop")
+            .unwrap()
+            .check_credentials(&env)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_channels_keep_separate_state() {
+        let store = MemoryStore::new();
+        let channels = Channels::from_env(&env());
+        let shop = channels.by_name("shop").unwrap().store(&store);
+        let default = Channel::default().store(&store);
+
+        shop.put("follower:U1", "shop").await.unwrap();
+        default.put("follower:U1", "default").await.unwrap();
+
+        assert_eq!(shop.get("follower:U1").await.unwrap(), Some("shop".into()));
+        assert_eq!(
+            store.get("shop:follower:U1").await.unwrap(),
+            Some("shop".into())
+        );
+        assert_eq!(
+            store.get("follower:U1").await.unwrap(),
+            Some("default".into())
+        );
+        assert_eq!(shop.keys("follower:").await.unwrap(), vec!["follower:U1"]);
+        assert_eq!(
+            default.keys("follower:").await.unwrap(),
+            vec!["follower:U1"]
+        );
+        shop.delete("follower:U1").await.unwrap();
+        assert!(shop.keys("follower:").await.unwrap().is_empty());
+        assert_eq!(
+            default.get("follower:U1").await.unwrap(),
+            Some("default".into())
+        );
+    }
+}
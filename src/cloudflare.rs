@@ -0,0 +1,288 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::time::Duration;
+use worker::*;
+
+use crate::channel::Channels;
+use crate::dolphin::{Dolphin, Secrets, WebhookError};
+use crate::line::{profile_path, LineClient, DEFAULT_API_BASE_URL};
+use crate::state::StateStore;
+use crate::types::{
+    AcquireControlRequest, Conversation, Profile, PushRequest, ReplyMessage, ReplyRequest,
+};
+
+impl Secrets for Env {
+    fn get(&self, key: &str) -> Option<String> {
+        self.secret(key)
+            .map(|v| v.to_string())
+            .or_else(|_| self.var(key).map(|v| v.to_string()))
+            .ok()
+    }
+}
+
+impl StateStore for kv::KvStore {
+    async fn get(&self, key: &str) -> crate::Result<Option<String>> {
+        Ok(kv::KvStore::get(self, key).text().await?)
+    }
+
+    async fn put(&self, key: &str, value: &str) -> crate::Result<()> {
+        kv::KvStore::put(self, key, value)?.execute().await?;
+        Ok(())
+    }
+
+    async fn put_with_ttl(&self, key: &str, value: &str, ttl: Duration) -> crate::Result<()> {
+        // KV rejects expirations shorter than 60 seconds
+        kv::KvStore::put(self, key, value)?
+            .expiration_ttl(ttl.as_secs().max(60))
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> crate::Result<()> {
+        kv::KvStore::delete(self, key).await?;
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> crate::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let mut cursor = None;
+        loop {
+            let mut list = self.list().prefix(prefix.to_string());
+            if let Some(cursor) = cursor {
+                list = list.cursor(cursor);
+            }
+            let page = list.execute().await?;
+            keys.extend(page.keys.into_iter().map(|key| key.name));
+            if page.list_complete {
+                return Ok(keys);
+            }
+            cursor = page.cursor;
+        }
+    }
+}
+
+/// LINE Messaging API client using the Workers `Fetch` API.
+struct FetchClient {
+    api_base_url: String,
+    channel_access_token: String,
+}
+
+impl FetchClient {
+    fn from_env(env: &impl Secrets) -> Result<Self> {
+        let channel_access_token = env
+            .get("LINE_CHANNEL_ACCESS_TOKEN")
+            .ok_or("LINE_CHANNEL_ACCESS_TOKEN must be set")?;
+        let api_base_url = env
+            .get("LINE_API_BASE_URL")
+            .unwrap_or_else(|| DEFAULT_API_BASE_URL.to_string())
+            .trim_end_matches('/')
+            .to_string();
+        Ok(FetchClient {
+            api_base_url,
+            channel_access_token,
+        })
+    }
+
+    async fn post<T: Serialize>(&self, path: &str, payload: &T) -> Result<()> {
+        let body = serde_json::to_string(payload)?;
+
+        let headers = Headers::new();
+        headers.set(
+            "Authorization",
+            &format!("Bearer {}", self.channel_access_token),
+        )?;
+        headers.set("Content-Type", "application/json")?;
+
+        let mut init = RequestInit::new();
+        init.with_method(Method::Post);
+        init.with_headers(headers);
+        init.with_body(Some(body.into()));
+
+        let url = format!("{}{}", self.api_base_url, path);
+        let request = Request::new_with_init(&url, &init)?;
+        let mut response = Fetch::Request(request).send().await?;
+
+        let status = response.status_code();
+        if !(200..300).contains(&status) {
+            let error_text = response.text().await?;
+            return Err(format!("LINE API error: {}", error_text).into());
+        }
+
+        Ok(())
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let headers = Headers::new();
+        headers.set(
+            "Authorization",
+            &format!("Bearer {}", self.channel_access_token),
+        )?;
+
+        let mut init = RequestInit::new();
+        init.with_method(Method::Get);
+        init.with_headers(headers);
+
+        let url = format!("{}{}", self.api_base_url, path);
+        let request = Request::new_with_init(&url, &init)?;
+        let mut response = Fetch::Request(request).send().await?;
+
+        let status = response.status_code();
+        if !(200..300).contains(&status) {
+            let error_text = response.text().await?;
+            return Err(format!("LINE API error: {}", error_text).into());
+        }
+
+        response.json().await
+    }
+}
+
+impl LineClient for FetchClient {
+    async fn reply(&self, reply_token: &str, messages: Vec<ReplyMessage>) -> crate::Result<()> {
+        let reply_request = ReplyRequest {
+            reply_token: reply_token.to_string(),
+            messages,
+        };
+        self.post("/v2/bot/message/reply", &reply_request)
+            .await
+            .map_err(|e| e.to_string().into())
+    }
+
+    async fn push(&self, to: &str, messages: Vec<ReplyMessage>) -> crate::Result<()> {
+        let push_request = PushRequest {
+            to: to.to_string(),
+            messages,
+        };
+        self.post("/v2/bot/message/push", &push_request)
+            .await
+            .map_err(|e| e.to_string().into())
+    }
+
+    async fn acquire_chat_control(&self, chat_id: &str, ttl: Duration) -> crate::Result<()> {
+        let acquire_request = AcquireControlRequest {
+            expired: true,
+            ttl: ttl.as_secs(),
+        };
+        let path = format!("/v2/bot/chat/{}/control/acquire", chat_id);
+        self.post(&path, &acquire_request)
+            .await
+            .map_err(|e| e.to_string().into())
+    }
+
+    async fn release_chat_control(&self, chat_id: &str) -> crate::Result<()> {
+        let path = format!("/v2/bot/chat/{}/control/release", chat_id);
+        self.post(&path, &serde_json::json!({}))
+            .await
+            .map_err(|e| e.to_string().into())
+    }
+
+    async fn get_profile(&self, user_id: &str, chat: Conversation<'_>) -> crate::Result<Profile> {
+        self.get(&profile_path(user_id, chat))
+            .await
+            .map_err(|e| e.to_string().into())
+    }
+}
+
+/// Forwards `log` records to the Workers console.
+struct ConsoleLogger;
+
+impl log::Log for ConsoleLogger {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        if record.level() <= log::Level::Warn {
+            console_error!("{}", record.args());
+        } else {
+            console_log!("{}", record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: ConsoleLogger = ConsoleLogger;
+
+#[derive(Serialize)]
+struct HealthResponse {
+    status: String,
+    version: String,
+}
+
+fn error_response(error: WebhookError) -> Result<Response> {
+    match error {
+        WebhookError::Unauthorized => Response::error("Unauthorized", 401),
+        WebhookError::BadRequest => Response::error("Bad Request", 400),
+        WebhookError::UnknownChannel => Response::error("Not Found", 404),
+    }
+}
+
+/// Verifies and parses a webhook for the channel it is routed to, then
+/// handles its events after the response has been sent.
+async fn webhook(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+    path_channel: Option<String>,
+) -> Result<Response> {
+    let env = ctx.env;
+    let kv = env.kv("DOLPHIN_REPLY_STATE")?;
+
+    let body_bytes = req.bytes().await?;
+    let signature = req.headers().get("x-line-signature")?;
+
+    let channel = match Channels::from_env(&env).route(path_channel.as_deref(), &body_bytes) {
+        Ok(channel) => channel,
+        Err(e) => return error_response(e),
+    };
+    let (channel_env, channel_kv) = (channel.env(&env), channel.store(&kv));
+    let line = FetchClient::from_env(&channel_env)?;
+
+    let dolphin = Dolphin::new(&channel_env, &channel_kv, &line);
+    let webhook_request = match dolphin.parse_webhook(&body_bytes, signature.as_deref()) {
+        Ok(webhook_request) => webhook_request,
+        Err(e) => return error_response(e),
+    };
+
+    // Answer LINE right away and handle the events after the response,
+    // one at a time so each conversation sees them in order
+    ctx.data.wait_until(async move {
+        let (env, kv) = (channel.env(&env), channel.store(&kv));
+        let dolphin = Dolphin::new(&env, &kv, &line);
+        dolphin.handle_events(&webhook_request.events).await;
+    });
+
+    Response::ok("")
+}
+
+#[event(fetch)]
+async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
+
+    let router = Router::with_data(ctx);
+
+    router
+        .get("/", |_, _| {
+            let health_response = HealthResponse {
+                status: "ok".to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            };
+            Response::from_json(&health_response)
+        })
+        .get_async("/webhook", |_, _| async move {
+            let health_response = HealthResponse {
+                status: "ok".to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            };
+            Response::from_json(&health_response)
+        })
+        .post_async("/webhook", |req, ctx| webhook(req, ctx, None))
+        .post_async("/webhook/:channel", |req, ctx| {
+            let channel = ctx.param("channel").cloned();
+            webhook(req, ctx, channel)
+        })
+        .run(req, env)
+        .await
+}
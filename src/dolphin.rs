@@ -1,24 +1,54 @@
-use crate::types::{ReplyMessage, ReplyRequest};
-use regex;
-use std::env;
-use std::fs;
-use tracing::{error, info};
+use base64::{engine::general_purpose, Engine as _};
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use crate::flex::{self, AnswerStyle};
+use crate::line::LineClient;
+use crate::postback::{PostbackAction, PostbackData};
+use crate::reply::ReplyBuilder;
+use crate::state::StateStore;
+use crate::sticker::{StickerConfig, StickerRef};
+use crate::types::{
+    Action, Conversation, EventKind, Mention, Message, QuickReply, ReplyMessage, Source,
+    Substitution, WebhookEvent, WebhookRequest,
+};
+use crate::Result;
+
+/// Access to secrets and plain configuration variables of the runtime.
+pub trait Secrets {
+    fn get(&self, key: &str) -> Option<String>;
+
+    /// Returns true when the variable is set to "true".
+    fn flag(&self, key: &str) -> bool {
+        self.get(key).map(|v| v == "true").unwrap_or(false)
+    }
+}
+
+impl Secrets for HashMap<String, String> {
+    fn get(&self, key: &str) -> Option<String> {
+        HashMap::get(self, key).cloned()
+    }
+}
 
 #[derive(Debug)]
-struct BroadcastConfig {
+pub struct BroadcastConfig {
     allowed_user_id: String,
     target_group_id: String,
 }
 
 impl BroadcastConfig {
-    fn from_env() -> Vec<Self> {
+    pub fn from_env(env: &impl Secrets) -> Vec<Self> {
         let mut configs = Vec::new();
 
         // Check for numbered configurations (DOLPHIN_USER_TO_GROUP1, DOLPHIN_USER_TO_GROUP2, etc.)
         for i in 1..=10 {
-            // Support up to 10 configurations
             let env_key = format!("DOLPHIN_USER_TO_GROUP{}", i);
-            if let Ok(var) = env::var(&env_key) {
+            if let Some(var) = env.get(&env_key) {
                 if let Some(config) = Self::parse_config(&var) {
                     configs.push(config);
                 }
@@ -26,7 +56,7 @@ impl BroadcastConfig {
         }
 
         // Also check for the original DOLPHIN_USER_TO_GROUP (for backward compatibility)
-        if let Ok(var) = env::var("DOLPHIN_USER_TO_GROUP") {
+        if let Some(var) = env.get("DOLPHIN_USER_TO_GROUP") {
             if let Some(config) = Self::parse_config(&var) {
                 configs.push(config);
             }
@@ -56,323 +86,2032 @@ impl BroadcastConfig {
             .iter()
             .find(|config| config.is_user_authorized(user_id))
     }
+}
+
+/// Computes the base64 HMAC-SHA256 `x-line-signature` of a webhook body.
+pub fn sign_body(body: &[u8], channel_secret: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(channel_secret.as_bytes())
+        .expect("HMAC can take key of any size");
+
+    mac.update(body);
+    let result = mac.finalize();
+    general_purpose::STANDARD.encode(result.into_bytes())
+}
+
+/// Verifies the base64 HMAC-SHA256 `x-line-signature` of a webhook body,
+/// comparing in constant time.
+pub fn verify_signature(body: &[u8], signature: &str, channel_secret: &str) -> bool {
+    let Ok(signature) = general_purpose::STANDARD.decode(signature) else {
+        return false;
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(channel_secret.as_bytes())
+        .expect("HMAC can take key of any size");
+
+    mac.update(body);
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MessageEntry {
+    user_id: String,
+    /// The LINE message ID, used to forget the message if it is unsent.
+    #[serde(default)]
+    message_id: Option<String>,
+    /// The text, empty for stickers.
+    message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sticker: Option<StickerRef>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MessageHistory {
+    entries: Vec<MessageEntry>,
+}
+
+impl MessageHistory {
+    fn new() -> Self {
+        MessageHistory {
+            entries: Vec::new(),
+        }
+    }
+
+    fn add_message(&mut self, user_id: String, message_id: String, message: String) {
+        self.push(MessageEntry {
+            user_id,
+            message_id: Some(message_id),
+            message,
+            sticker: None,
+        });
+    }
+
+    fn add_sticker(&mut self, user_id: String, message_id: String, sticker: StickerRef) {
+        self.push(MessageEntry {
+            user_id,
+            message_id: Some(message_id),
+            message: String::new(),
+            sticker: Some(sticker),
+        });
+    }
+
+    /// Removes an unsent message. Returns true if it was in the history.
+    fn remove_message(&mut self, message_id: &str) -> bool {
+        let len = self.entries.len();
+        self.entries
+            .retain(|entry| entry.message_id.as_deref() != Some(message_id));
+        self.entries.len() != len
+    }
+
+    fn push(&mut self, entry: MessageEntry) {
+        self.entries.push(entry);
+        // Keep only the last 2 entries
+        if self.entries.len() > 2 {
+            self.entries.remove(0);
+        }
+    }
 
-    fn has_authorized_user(configs: &[Self]) -> bool {
-        // print configs
-        println!("configs: {:?}", configs);
-        !configs.is_empty()
+    fn get_last_entry(&self) -> Option<&MessageEntry> {
+        self.entries.last()
     }
 }
 
-fn get_reply_state_file() -> String {
-    env::var("REPLY_STATE_FILE").unwrap_or_else(|_| "reply_state.txt".to_string())
+/// A user who follows the bot, stored under `follower:<user ID>` and
+/// deleted on unfollow. Followers are counted by listing these keys.
+#[derive(Serialize, Deserialize, Debug)]
+struct FollowerRecord {
+    /// When the user followed the bot (ms since the Unix epoch).
+    followed_at: i64,
 }
 
-fn is_replies_enabled() -> bool {
-    let state_file = get_reply_state_file();
-    if let Ok(content) = fs::read_to_string(&state_file) {
-        content.trim() == "enabled"
-    } else {
-        // Default to enabled if file doesn't exist or can't be read
-        true
-    }
-}
-
-fn set_replies_enabled(enabled: bool) -> Result<(), Box<dyn std::error::Error>> {
-    let state_file = get_reply_state_file();
-    let state = if enabled { "enabled" } else { "disabled" };
-    fs::write(&state_file, state)?;
-    Ok(())
-}
-
-pub async fn send_reply(
-    reply_token: &str,
-    text: &str,
-    source: &crate::Source,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Get user ID for checksum calculation
-    let user_id = source.user_id.as_deref().unwrap_or("unknown");
-
-    // Check if source has group-id
-    let has_group_id = source.group_id.is_some();
-
-    // For group messages, check if the message starts with "@dolphin"
-    let trimmed_text = text.trim().to_lowercase();
-    let is_dolphin_message = trimmed_text.starts_with("@dolphin");
-    let is_off_command = trimmed_text.starts_with("@off");
-    let is_on_command = trimmed_text.starts_with("@on");
-
-    // Check for @all+XXXX pattern (send to specific group by last 4 digits) or "@all"
-    let all_plus_pattern = regex::Regex::new(r"^@all\+(\w{4})").unwrap();
-    let target_group_digits = all_plus_pattern
-        .captures(&trimmed_text)
-        .and_then(|cap| cap.get(1))
-        .map(|m| m.as_str().to_string());
-    let is_all_plus_message = target_group_digits.is_some();
-    let is_all_message = target_group_digits.is_none() && trimmed_text.starts_with("@all");
-
-    // Handle @off and @on commands from authorized user
-    if is_off_command || is_on_command {
-        let broadcast_configs = BroadcastConfig::from_env();
-        if BroadcastConfig::has_authorized_user(&broadcast_configs) {
-            if BroadcastConfig::find_by_user_id(&broadcast_configs, user_id).is_some() {
-                let enable = is_on_command;
-                if set_replies_enabled(enable).is_ok() {
-                    let status = if enable { "enabled" } else { "disabled" };
-                    let reply_text = format!("🔧 Replies have been {}", status);
-                    send_line_reply(reply_token, &reply_text).await?;
-                    info!("Reply status changed to {} by user {}", status, user_id);
-                    return Ok(());
-                } else {
-                    error!("Failed to save reply state");
-                    let reply_text = "❌ Failed to change reply status".to_string();
-                    send_line_reply(reply_token, &reply_text).await?;
+/// A group the bot is a member of, stored under `group:<group ID>`. The
+/// known groups are listed from these keys.
+///
+/// Timestamps are in ms since the Unix epoch, as in webhook events.
+#[derive(Serialize, Deserialize, Debug)]
+struct GroupRecord {
+    first_seen: i64,
+    last_active: i64,
+}
+
+/// Greeting for new group members; `{name}` mentions them.
+const DEFAULT_MEMBER_WELCOME: &str = "🐬 Welcome {name}! Ask me anything with @dolphin";
+
+/// `{name}` in a farewell for a member whose profile can't be looked up.
+const FAREWELL_NAME: &str = "friend";
+
+/// Per-group member templates, stored under `group_templates:<group ID>`.
+/// `None` means the default, an empty string means turned off.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct GroupTemplates {
+    welcome: Option<String>,
+    farewell: Option<String>,
+}
+
+impl GroupTemplates {
+    fn welcome(&self) -> &str {
+        self.welcome.as_deref().unwrap_or(DEFAULT_MEMBER_WELCOME)
+    }
+}
+
+const FOLLOWER_PREFIX: &str = "follower:";
+const GROUP_PREFIX: &str = "group:";
+
+fn follower_key(user_id: &str) -> String {
+    format!("{}{}", FOLLOWER_PREFIX, user_id)
+}
+
+fn group_key(group_id: &str) -> String {
+    format!("{}{}", GROUP_PREFIX, group_id)
+}
+
+fn templates_key(group_id: &str) -> String {
+    format!("group_templates:{}", group_id)
+}
+
+/// A group's own [`AnswerStyle`], overriding `DOLPHIN_ANSWER_STYLE`.
+fn answer_style_key(group_id: &str) -> String {
+    format!("answer_style:{}", group_id)
+}
+
+/// Renders a welcome template as a `textV2` message in which `{name}`
+/// mentions every new member.
+fn render_welcome(template: &str, user_ids: &[&str]) -> ReplyMessage {
+    if !template.contains("{name}") {
+        return ReplyMessage::text(template);
+    }
+
+    let mut substitution = BTreeMap::new();
+    let mut names = Vec::new();
+    for (i, user_id) in user_ids.iter().enumerate() {
+        let key = format!("user{}", i + 1);
+        names.push(format!("{{{}}}", key));
+        substitution.insert(key, Substitution::mention_user(user_id));
+    }
+
+    let text = template
+        .replace('{', "{{")
+        .replace('}', "}}")
+        .replace("{{name}}", &names.join(", "));
+    ReplyMessage::text_v2(&text, substitution)
+}
+
+/// How stale a group's `last_active` may get before it is rewritten.
+const GROUP_ACTIVITY_RESOLUTION_MS: i64 = 60 * 60 * 1000;
+
+/// Why a webhook request was rejected before any event was handled.
+#[derive(Debug, PartialEq)]
+pub enum WebhookError {
+    /// Missing or invalid `x-line-signature` (HTTP 401).
+    Unauthorized,
+    /// The body is not a valid webhook request (HTTP 400).
+    BadRequest,
+    /// `/webhook/{channel}` names a channel that is not configured (HTTP 404).
+    UnknownChannel,
+}
+
+/// Greeting for new followers; override with `DOLPHIN_WELCOME_MESSAGE`.
+const DEFAULT_WELCOME_MESSAGE: &str = "🐬 Hi, I'm the dolphin! Ask me anything with \"@dolphin <your question>\" and I'll answer yes or no.";
+
+/// How long handled webhook event IDs are remembered to skip redeliveries.
+const HANDLED_EVENT_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// What to do with events from chats where the bot is a module channel on
+/// standby; set with `DOLPHIN_STANDBY`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum StandbyMode {
+    /// Ignore them (`skip`, the default).
+    #[default]
+    Skip,
+    /// Keep group activity and message history up to date without answering
+    /// (`observe`).
+    Observe,
+}
+
+impl StandbyMode {
+    fn from_env(env: &impl Secrets) -> Self {
+        match env.get("DOLPHIN_STANDBY").as_deref() {
+            Some("observe") => StandbyMode::Observe,
+            _ => StandbyMode::Skip,
+        }
+    }
+}
+
+/// How long a chat taken over with Chat Control stays with the dolphin if
+/// handing it back fails.
+const CHAT_CONTROL_TTL: Duration = Duration::from_secs(60);
+
+/// Where the answers to an event go.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReplyTarget<'r> {
+    pub reply_token: Option<&'r str>,
+    /// Chat to push to when there is no reply token or the reply fails.
+    pub push_fallback: Option<&'r str>,
+    /// `quoteToken` of the message being answered, for quoting it.
+    pub quote_token: Option<&'r str>,
+    /// Chat the event came from, for pushing messages that don't fit in
+    /// one reply.
+    pub chat_id: Option<&'r str>,
+}
+
+impl<'r> ReplyTarget<'r> {
+    /// Answers with the reply token only.
+    pub fn token(reply_token: &'r str) -> Self {
+        ReplyTarget {
+            reply_token: Some(reply_token),
+            push_fallback: None,
+            quote_token: None,
+            chat_id: None,
+        }
+    }
+
+    /// Picks the reply target for an event. Redelivered events may carry a
+    /// stale reply token, so they fall back to pushing into their chat.
+    pub fn for_event(event: &'r WebhookEvent) -> Self {
+        ReplyTarget {
+            reply_token: event.reply_token(),
+            push_fallback: event
+                .delivery_context
+                .is_redelivery
+                .then(|| event.source.chat_id())
+                .flatten(),
+            quote_token: match &event.kind {
+                EventKind::Message { message, .. } => message.quote_token(),
+                _ => None,
+            },
+            chat_id: event.source.chat_id(),
+        }
+    }
+}
+
+/// The commands the dolphin understands, matched case-insensitively at the
+/// start of a message.
+const COMMANDS: [&str; 9] = [
+    "@dolphin",
+    "@all",
+    "@off",
+    "@on",
+    "@followers",
+    "@groups",
+    "@welcome",
+    "@farewell",
+    "@style",
+];
+
+fn is_command(text: &str) -> bool {
+    let text = text.trim().to_lowercase();
+    COMMANDS.iter().any(|command| text.starts_with(command))
+}
+
+/// The text to handle for a text message. Tapping the bot's name mentions it
+/// instead of typing "@dolphin", so such messages become questions too,
+/// unless what follows the mention is a command of its own.
+fn command_text(text: &str, mention: Option<&Mention>) -> String {
+    match mention.filter(|m| m.mentions_self()) {
+        Some(mention) => {
+            let text = mention.strip_self(text).trim().to_string();
+            if is_command(&text) {
+                text
+            } else {
+                format!("@dolphin {}", text)
+            }
+        }
+        None => text.to_string(),
+    }
+}
+
+/// Returns where to answer an event, or `None` when it can't be answered.
+fn reply_target(event: &WebhookEvent) -> Option<ReplyTarget<'_>> {
+    let target = ReplyTarget::for_event(event);
+    if target.reply_token.is_none() && target.push_fallback.is_none() {
+        error!("No reply token found for event: {}", event.webhook_event_id);
+        return None;
+    }
+    Some(target)
+}
+
+/// The runtime-agnostic bot: command handling and the oracle, on top of the
+/// runtime's secrets, state store and LINE client.
+pub struct Dolphin<'a, E, S, L> {
+    env: &'a E,
+    store: &'a S,
+    line: &'a L,
+    disable_repeat_detection: bool,
+    disable_redelivery_detection: bool,
+    standby: StandbyMode,
+    chat_control: bool,
+    answer_style: AnswerStyle,
+    flex_image_url: Option<String>,
+    quick_replies: bool,
+}
+
+impl<'a, E: Secrets, S: StateStore, L: LineClient> Dolphin<'a, E, S, L> {
+    pub fn new(env: &'a E, store: &'a S, line: &'a L) -> Self {
+        Dolphin {
+            env,
+            store,
+            line,
+            disable_repeat_detection: env.flag("DISABLE_REPEAT_DETECTION"),
+            disable_redelivery_detection: env.flag("DISABLE_REDELIVERY_DETECTION"),
+            standby: StandbyMode::from_env(env),
+            chat_control: env.flag("DOLPHIN_CHAT_CONTROL"),
+            answer_style: AnswerStyle::from_env(env),
+            flex_image_url: env.get("DOLPHIN_FLEX_IMAGE_URL"),
+            quick_replies: !env.flag("DISABLE_QUICK_REPLIES"),
+        }
+    }
+
+    /// Verifies, parses and handles a raw webhook request body.
+    pub async fn handle_webhook(
+        &self,
+        body: &[u8],
+        signature: Option<&str>,
+    ) -> std::result::Result<(), WebhookError> {
+        let webhook_request = self.parse_webhook(body, signature)?;
+        self.handle_events(&webhook_request.events).await;
+        Ok(())
+    }
+
+    /// Verifies and parses a raw webhook request body without handling its
+    /// events, so a runtime can acknowledge the request before the work is done.
+    pub fn parse_webhook(
+        &self,
+        body: &[u8],
+        signature: Option<&str>,
+    ) -> std::result::Result<WebhookRequest, WebhookError> {
+        // Skip signature verification in dev mode
+        if self.env.flag("SKIP_SIGNATURE_VERIFICATION") {
+            warn!("⚠️  Dev mode: Skipping signature verification");
+        } else {
+            let Some(channel_secret) = self.env.get("LINE_CHANNEL_SECRET") else {
+                error!("LINE_CHANNEL_SECRET must be set");
+                return Err(WebhookError::Unauthorized);
+            };
+
+            let Some(signature) = signature else {
+                error!("Missing signature header");
+                return Err(WebhookError::Unauthorized);
+            };
+
+            if !verify_signature(body, signature, &channel_secret) {
+                error!("Invalid signature");
+                return Err(WebhookError::Unauthorized);
+            }
+        }
+
+        serde_json::from_slice(body).map_err(|e| {
+            error!("Failed to parse webhook request: {}", e);
+            WebhookError::BadRequest
+        })
+    }
+
+    /// Handles a batch of events one after another, in delivery order.
+    pub async fn handle_events(&self, events: &[WebhookEvent]) {
+        for event in events {
+            self.handle_event(event).await;
+        }
+    }
+
+    /// Handles a single webhook event: text messages, follows and unfollows.
+    ///
+    /// Events handled successfully are remembered by their `webhookEventId`,
+    /// so a redelivery of an event that was already handled is skipped while
+    /// one whose answer failed gets another try.
+    pub async fn handle_event(&self, event: &WebhookEvent) {
+        if self.is_handled_redelivery(event).await {
+            info!("Skipping already handled event: {}", event.webhook_event_id);
+            return;
+        }
+        match self.dispatch_event(event).await {
+            Ok(()) => self.remember_event(event).await,
+            Err(e) => error!("Failed to send reply: {}", e),
+        }
+    }
+
+    async fn dispatch_event(&self, event: &WebhookEvent) -> Result<()> {
+        if event.is_standby() {
+            self.handle_standby_event(event).await;
+            return Ok(());
+        }
+
+        match &event.kind {
+            EventKind::Message { message, .. } => {
+                if let Some(chat_id) = event.source.group_chat_id() {
+                    self.touch_group(chat_id, event.timestamp).await;
+                }
+                let Some(target) = reply_target(event) else {
                     return Ok(());
+                };
+                match message {
+                    Message::Text {
+                        id, text, mention, ..
+                    } => {
+                        let text = command_text(text, mention.as_ref());
+                        self.send_reply(&target, id, &text, &event.source).await
+                    }
+                    Message::Sticker {
+                        id,
+                        package_id,
+                        sticker_id,
+                        keywords,
+                        ..
+                    } => {
+                        let sticker = StickerRef {
+                            package_id: package_id.clone(),
+                            sticker_id: sticker_id.clone(),
+                        };
+                        self.send_sticker_reply(&target, id, &sticker, keywords, &event.source)
+                            .await
+                    }
+                    _ => Ok(()),
+                }?;
+            }
+            EventKind::Follow { .. } => self.handle_follow(event).await,
+            EventKind::Unfollow => self.handle_unfollow(event).await,
+            EventKind::Join { .. } => {
+                if let Some(chat_id) = event.source.group_chat_id() {
+                    info!("Joined group chat: {}", chat_id);
+                    self.touch_group(chat_id, event.timestamp).await;
                 }
             }
+            EventKind::Leave => {
+                if let Some(chat_id) = event.source.group_chat_id() {
+                    info!("Left group chat: {}", chat_id);
+                    if let Err(e) = self.unregister_group(chat_id).await {
+                        error!("Failed to unregister group chat {}: {}", chat_id, e);
+                    }
+                    let _ = self.store.delete(&templates_key(chat_id)).await;
+                    let _ = self.store.delete(&answer_style_key(chat_id)).await;
+                }
+            }
+            EventKind::MemberJoined { joined, .. } => {
+                self.handle_member_joined(event, &joined.members).await
+            }
+            EventKind::MemberLeft { left } => self.handle_member_left(event, &left.members).await,
+            EventKind::Unsend { unsend } => self.handle_unsend(event, &unsend.message_id).await,
+            EventKind::Postback { postback, .. } => {
+                self.handle_postback(event, &postback.data).await
+            }
+            _ => {}
         }
+        Ok(())
     }
 
-    // Check if replies are enabled
-    if !is_replies_enabled() && has_group_id {
-        info!(
-            "Replies are disabled, ignoring message from user {}",
-            user_id
-        );
-        return Ok(());
+    /// Handles an event from a chat where another channel is active. With
+    /// `DOLPHIN_CHAT_CONTROL`, an `@dolphin` question takes the chat over
+    /// long enough to answer it; anything else follows the standby mode.
+    async fn handle_standby_event(&self, event: &WebhookEvent) {
+        if let EventKind::Message {
+            message: Message::Text {
+                id, text, mention, ..
+            },
+            ..
+        } = &event.kind
+        {
+            let text = command_text(text, mention.as_ref());
+            let is_question = text.trim().to_lowercase().starts_with("@dolphin");
+            if let Some(chat_id) = event.source.chat_id().filter(|_| self.chat_control) {
+                if is_question {
+                    self.take_over_chat(event, chat_id, id, &text).await;
+                    return;
+                }
+            }
+        }
+
+        match self.standby {
+            StandbyMode::Skip => info!("Skipping standby event: {}", event.webhook_event_id),
+            StandbyMode::Observe => self.observe_event(event).await,
+        }
     }
 
-    if !is_dolphin_message && !is_all_message && !is_all_plus_message {
-        if has_group_id {
-            // Ignore messages that don't start with "@dolphin" or "@all" in group chats
-            return Ok(());
+    /// Acquires control of a chat, answers a question there and hands the
+    /// chat back to the primary channel.
+    async fn take_over_chat(
+        &self,
+        event: &WebhookEvent,
+        chat_id: &str,
+        message_id: &str,
+        text: &str,
+    ) {
+        if let Err(e) = self
+            .line
+            .acquire_chat_control(chat_id, CHAT_CONTROL_TTL)
+            .await
+        {
+            error!("Failed to acquire chat control of {}: {}", chat_id, e);
+            return;
         }
+        info!("Took over chat {}", chat_id);
 
-        // For direct messages, reply to all messages
-        let reply_text = create_reply(user_id, text);
-        send_line_reply(reply_token, &reply_text).await?;
-        info!("Reply sent (user_id: {}): {}", user_id, text);
-        return Ok(());
+        // Standby events carry no reply token, so the answer is pushed
+        let target = ReplyTarget {
+            reply_token: None,
+            push_fallback: Some(chat_id),
+            quote_token: ReplyTarget::for_event(event).quote_token,
+            chat_id: Some(chat_id),
+        };
+        if let Err(e) = self
+            .send_reply(&target, message_id, text, &event.source)
+            .await
+        {
+            error!("Failed to send reply: {}", e);
+        }
+
+        if let Err(e) = self.line.release_chat_control(chat_id).await {
+            error!("Failed to release chat control of {}: {}", chat_id, e);
+        }
     }
 
-    // Get broadcast configs once to avoid repeated parsing
-    let broadcast_configs = BroadcastConfig::from_env();
-    let (message_content, is_broadcast, authorized_broadcast, target_group_id) =
-        if is_all_plus_message {
-            // Extract the message content after "@all+XXXX"
-            let prefix_len = format!("@all+{}", target_group_digits.as_ref().unwrap()).len();
-            let content = text.trim()[prefix_len..].trim();
+    /// Keeps track of a group chat without answering: its activity, its
+    /// message history and unsent messages.
+    async fn observe_event(&self, event: &WebhookEvent) {
+        let Some(group_id) = event.source.group_chat_id() else {
+            return;
+        };
+        let user_id = event.source.user_id.as_deref().unwrap_or("unknown");
 
-            // For @all+XXXX, find the group that ends with XXXX from all configured groups
-            let target_group = broadcast_configs
-                .iter()
-                .find(|config| {
-                    config
-                        .target_group_id
-                        .ends_with(target_group_digits.as_ref().unwrap().as_str())
-                })
-                .map(|config| config.target_group_id.clone());
+        match &event.kind {
+            EventKind::Message { message, .. } => {
+                self.touch_group(group_id, event.timestamp).await;
+                if self.disable_repeat_detection {
+                    return;
+                }
+                match message {
+                    Message::Text { id, text, .. } => {
+                        self.record_message(group_id, user_id, id, text).await
+                    }
+                    Message::Sticker {
+                        id,
+                        package_id,
+                        sticker_id,
+                        ..
+                    } => {
+                        let sticker = StickerRef {
+                            package_id: package_id.clone(),
+                            sticker_id: sticker_id.clone(),
+                        };
+                        self.record_sticker(group_id, user_id, id, &sticker).await
+                    }
+                    _ => {}
+                }
+            }
+            EventKind::Unsend { unsend } => self.handle_unsend(event, &unsend.message_id).await,
+            _ => {}
+        }
+    }
 
-            let authorized = target_group.is_some();
+    /// Purges an unsent message from the group chat's history.
+    async fn handle_unsend(&self, event: &WebhookEvent, message_id: &str) {
+        let Some(group_id) = event.source.group_chat_id() else {
+            return;
+        };
 
-            (content.to_string(), true, authorized, target_group)
-        } else if is_all_message {
-            // Extract the message content after "@all"
-            let content = text.trim()[4..].trim(); // Remove "@all" prefix
+        let mut history = self.get_message_history(group_id).await;
+        if history.remove_message(message_id) {
+            info!("Forgot unsent message {} in group {}", message_id, group_id);
+            if let Err(e) = self.save_message_history(group_id, &history).await {
+                error!("Failed to purge unsent message {}: {}", message_id, e);
+            }
+        }
+    }
 
-            // Check if user is authorized to broadcast
-            let authorized =
-                BroadcastConfig::find_by_user_id(&broadcast_configs, user_id).is_some();
+    /// Routes a postback to the handler of its action.
+    async fn handle_postback(&self, event: &WebhookEvent, data: &str) {
+        let Some(action) = PostbackData::parse(data)
+            .as_ref()
+            .and_then(PostbackAction::from_data)
+        else {
+            warn!("Ignoring unknown postback: {}", data);
+            return;
+        };
+        let Some(target) = reply_target(event) else {
+            return;
+        };
+        // Postbacks follow up on answers, so they stay quiet in group chats
+        // where replies are off just like questions do
+        if event.source.group_chat_id().is_some() && !self.is_replies_enabled().await {
+            return;
+        }
 
-            (content.to_string(), true, authorized, None)
-        } else {
-            // Extract the message content after "@dolphin"
-            let content = text.trim()[8..].trim(); // Remove "@dolphin" prefix
-            (content.to_string(), false, false, None)
+        let result = match action {
+            PostbackAction::Ask { question } => {
+                self.answer_question(&target, &question, &event.source)
+                    .await
+            }
+            PostbackAction::Why { question, answer } => {
+                self.send_line_reply(&target, &explain_answer(&question, &answer))
+                    .await
+            }
+            PostbackAction::Flip => {
+                self.send_line_reply(&target, flip_coin(event.timestamp))
+                    .await
+            }
         };
+        if let Err(e) = result {
+            error!("Failed to handle postback {}: {}", data, e);
+        }
+    }
 
-    if message_content.is_empty() {
-        return Ok(());
-    }
-
-    // Create response message
-    let reply_text = create_response_msg(
-        user_id,
-        &message_content,
-        has_group_id,
-        is_broadcast,
-        authorized_broadcast,
-        is_all_plus_message,
-        &target_group_id,
-        &target_group_digits,
-        &broadcast_configs,
-    )
-    .await;
-
-    send_line_reply(reply_token, &reply_text).await?;
-    let group_id = source.group_id.as_deref().unwrap_or("unknown");
-    info!(
-        "Reply sent (group_id:{}, broadcast:{}, user_id:{}): {}",
-        group_id,
-        is_broadcast,
-        &user_id[0..4],
-        message_content
-    );
-    Ok(())
-}
-
-/// Creates a response message based on the message context
-async fn create_response_msg(
-    user_id: &str,
-    message_content: &str,
-    has_group_id: bool,
-    is_broadcast: bool,
-    authorized_broadcast: bool,
-    is_all_plus_message: bool,
-    target_group_id: &Option<String>,
-    target_group_digits: &Option<String>,
-    broadcast_configs: &[BroadcastConfig],
-) -> String {
-    if has_group_id {
-        // For @dolphin and @all messages, use the standard checksum logic
-        create_reply(user_id, message_content)
-    } else {
-        if authorized_broadcast {
-            // Send broadcast message to target group
-            let target_group = if let Some(group_id) = target_group_id {
-                // @all+XXXX case - use the found group
-                group_id.clone()
-            } else {
-                // @all case - use user's configured group
-                BroadcastConfig::find_by_user_id(broadcast_configs, user_id)
-                    .map(|config| config.target_group_id.clone())
-                    .unwrap_or_default()
-            };
+    /// Answers a question the way `@dolphin <question>` would.
+    async fn answer_question(
+        &self,
+        target: &ReplyTarget<'_>,
+        question: &str,
+        source: &Source,
+    ) -> Result<()> {
+        let user_id = source.user_id.as_deref().unwrap_or("unknown");
+        self.send_answer(target, question, &create_reply(user_id, question), source)
+            .await
+    }
 
-            if !target_group.is_empty() {
-                if let Err(e) = send_push_message(&target_group, message_content).await {
-                    error!("Failed to send broadcast message: {}", e);
-                    format!("❌ Failed to broadcast message: \"{}\"", message_content)
-                } else {
-                    format!(
-                        "📢 Broadcast message sent to group: \"{}\"",
-                        message_content
-                    )
-                }
+    /// Sends the oracle's answer to a question in the chat's answer style,
+    /// with quick reply buttons to keep going. Plain text is the fallback
+    /// when a Flex Message can't be sent.
+    async fn send_answer(
+        &self,
+        target: &ReplyTarget<'_>,
+        question: &str,
+        answer: &str,
+        source: &Source,
+    ) -> Result<()> {
+        let with_buttons = |message: ReplyMessage| {
+            if self.quick_replies {
+                message.with_quick_reply(answer_buttons(question, answer))
             } else {
-                format!("❌ Broadcast configuration not found")
+                message
             }
-        } else if is_broadcast {
-            // User not authorized to broadcast
-            if is_all_plus_message {
-                format!(
-                    "❌ No group found with last 4 digits: {}",
-                    target_group_digits.as_ref().unwrap_or(&"".to_string())
-                )
-            } else {
-                format!("❌ You are not authorized to use @all broadcasts")
+        };
+
+        if self.answer_style(source).await == AnswerStyle::Flex {
+            let asker = self.asker_name(source).await;
+            let image_url = self.flex_image_url.as_deref();
+            let bubble = flex::oracle_bubble(question, answer, asker.as_deref(), image_url);
+            match self
+                .send_line_messages(target, vec![with_buttons(bubble)])
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!("Flex answer failed ({}), sending text instead", e),
             }
-        } else {
-            create_reply(user_id, message_content)
         }
+        let reply = answer_text(target, source, answer);
+        self.send_line_messages(target, vec![with_buttons(reply)])
+            .await
     }
-}
-
-/// Creates a reply based on checksum of user ID and message
-/// Returns "yes" if the sum is even, "no" if odd
-/// Special case: if message contains both "buy" and "nuclear", always returns "yes"
-fn create_reply(user_id: &str, message: &str) -> String {
-    let lower_message = message.to_lowercase();
 
-    // Special case: if message contains both "buy" and "nuclear", always return "yes"
-    if lower_message.contains("buy") && lower_message.contains("nuclear") {
-        return "yes".to_string();
+    /// The answer style of a chat: its own for groups and rooms that set
+    /// one, the deployment's otherwise.
+    async fn answer_style(&self, source: &Source) -> AnswerStyle {
+        match source.group_chat_id() {
+            Some(group_id) => self.group_answer_style(group_id).await,
+            None => self.answer_style,
+        }
     }
 
-    // Calculate checksum: sum of all ASCII values in user_id + message
-    let user_sum: u32 = user_id.chars().map(|c| c as u32).sum();
-    let message_sum: u32 = message.chars().map(|c| c as u32).sum();
-    let total_sum = user_sum + message_sum;
+    async fn group_answer_style(&self, group_id: &str) -> AnswerStyle {
+        match self.store.get(&answer_style_key(group_id)).await {
+            Ok(Some(style)) => AnswerStyle::parse(&style).unwrap_or(self.answer_style),
+            _ => self.answer_style,
+        }
+    }
 
-    // Return "yes" if even, "no" if odd
-    if total_sum % 2 == 0 {
-        "yes".to_string()
-    } else {
-        "no".to_string()
+    /// The display name of whoever sent an event, if LINE tells.
+    async fn asker_name(&self, source: &Source) -> Option<String> {
+        let user_id = source.user_id.as_deref()?;
+        match self.line.get_profile(user_id, source.conversation()?).await {
+            Ok(profile) => Some(profile.display_name),
+            Err(e) => {
+                warn!("Failed to look up profile of {}: {}", user_id, e);
+                None
+            }
+        }
     }
-}
 
-async fn send_line_reply(
-    reply_token: &str,
-    reply_text: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    // Validate reply token
-    if reply_token.trim().is_empty() {
-        return Err("Reply token cannot be empty".into());
+    /// Greets new group members with the group's welcome template.
+    async fn handle_member_joined(&self, event: &WebhookEvent, members: &[Source]) {
+        let Some(group_id) = event.source.group_chat_id() else {
+            return;
+        };
+        if !self.is_replies_enabled().await {
+            return;
+        }
+
+        let templates = self.get_group_templates(group_id).await;
+        let template = templates.welcome();
+        let user_ids: Vec<&str> = members
+            .iter()
+            .filter_map(|member| member.user_id.as_deref())
+            .collect();
+        if template.trim().is_empty() || user_ids.is_empty() {
+            return;
+        }
+
+        let Some(target) = reply_target(event) else {
+            return;
+        };
+        let greeting = render_welcome(template, &user_ids);
+        if let Err(e) = self.send_line_messages(&target, vec![greeting]).await {
+            error!("Failed to greet new members of {}: {}", group_id, e);
+        }
     }
 
-    let channel_access_token =
-        env::var("LINE_CHANNEL_ACCESS_TOKEN").expect("LINE_CHANNEL_ACCESS_TOKEN must be set");
+    /// Says goodbye with the group's farewell template, if it has one.
+    /// `memberLeft` events carry no reply token, so this is a push.
+    async fn handle_member_left(&self, event: &WebhookEvent, members: &[Source]) {
+        let Some(group_id) = event.source.group_chat_id() else {
+            return;
+        };
+        if !self.is_replies_enabled().await {
+            return;
+        }
 
-    let reply_request = ReplyRequest {
-        reply_token: reply_token.to_string(),
-        messages: vec![ReplyMessage {
-            message_type: "text".to_string(),
-            text: reply_text.to_string(),
-        }],
-    };
+        let templates = self.get_group_templates(group_id).await;
+        let Some(farewell) = templates.farewell.filter(|f| !f.trim().is_empty()) else {
+            return;
+        };
+        let farewell = if farewell.contains("{name}") {
+            let names = self.left_member_names(members).await;
+            farewell.replace("{name}", &names.join(", "))
+        } else {
+            farewell
+        };
+        if let Err(e) = self.send_push_message(group_id, &farewell).await {
+            error!("Failed to say farewell in {}: {}", group_id, e);
+        }
+    }
+
+    /// Display names for a farewell. Members who left can't be looked up in
+    /// the group any more, so only those who still follow the bot have a
+    /// name; anyone else is a "friend".
+    async fn left_member_names(&self, members: &[Source]) -> Vec<String> {
+        let mut names = Vec::new();
+        for user_id in members.iter().filter_map(|m| m.user_id.as_deref()) {
+            let name = match self
+                .line
+                .get_profile(user_id, Conversation::User(user_id))
+                .await
+            {
+                Ok(profile) => profile.display_name,
+                Err(_) => FAREWELL_NAME.to_string(),
+            };
+            names.push(name);
+        }
+        if names.is_empty() {
+            names.push(FAREWELL_NAME.to_string());
+        }
+        names
+    }
 
-    let client = reqwest::Client::new();
+    /// Records the new follower and greets them.
+    async fn handle_follow(&self, event: &WebhookEvent) {
+        let Some(user_id) = event.source.user_id.as_deref() else {
+            return;
+        };
 
-    let response = client
-        .post("https://api.line.me/v2/bot/message/reply")
-        .header("Authorization", format!("Bearer {}", channel_access_token))
-        .header("Content-Type", "application/json")
-        .json(&reply_request)
-        .send()
-        .await?;
+        if let Err(e) = self.add_follower(user_id, event.timestamp).await {
+            error!("Failed to record follower {}: {}", user_id, e);
+        }
+        info!("New follower: {}", user_id);
 
-    if !response.status().is_success() {
-        let error_text = response.text().await?;
-        return Err(format!("LINE API error: {}", error_text).into());
+        let greeting = self
+            .env
+            .get("DOLPHIN_WELCOME_MESSAGE")
+            .unwrap_or_else(|| DEFAULT_WELCOME_MESSAGE.to_string());
+        if greeting.trim().is_empty() {
+            return;
+        }
+        let Some(target) = reply_target(event) else {
+            return;
+        };
+        if let Err(e) = self.send_line_reply(&target, &greeting).await {
+            error!("Failed to send welcome message: {}", e);
+        }
     }
 
-    Ok(())
-}
+    /// Forgets everything stored about a user who blocked the bot.
+    async fn handle_unfollow(&self, event: &WebhookEvent) {
+        let Some(user_id) = event.source.user_id.as_deref() else {
+            return;
+        };
 
-async fn send_push_message(to: &str, text: &str) -> Result<(), Box<dyn std::error::Error>> {
-    let channel_access_token =
-        env::var("LINE_CHANNEL_ACCESS_TOKEN").expect("LINE_CHANNEL_ACCESS_TOKEN must be set");
+        if let Err(e) = self.remove_follower(user_id).await {
+            error!("Failed to remove follower {}: {}", user_id, e);
+        }
+        info!("Lost follower: {}", user_id);
+    }
 
-    #[derive(serde::Serialize)]
-    struct PushRequest {
-        to: String,
-        messages: Vec<ReplyMessage>,
+    /// Returns true for redeliveries of events that were handled before.
+    async fn is_handled_redelivery(&self, event: &WebhookEvent) -> bool {
+        if !event.delivery_context.is_redelivery {
+            return false;
+        }
+        if !self.disable_redelivery_detection {
+            let key = format!("event:{}", event.webhook_event_id);
+            if let Ok(Some(_)) = self.store.get(&key).await {
+                return true;
+            }
+        }
+        info!("Handling redelivered event: {}", event.webhook_event_id);
+        false
     }
 
-    let push_request = PushRequest {
-        to: to.to_string(),
-        messages: vec![ReplyMessage {
-            message_type: "text".to_string(),
-            text: text.to_string(),
-        }],
-    };
+    /// Marks an event as handled, one state write per event unless
+    /// `DISABLE_REDELIVERY_DETECTION` is set.
+    async fn remember_event(&self, event: &WebhookEvent) {
+        if self.disable_redelivery_detection {
+            return;
+        }
+        let key = format!("event:{}", event.webhook_event_id);
+        if let Err(e) = self
+            .store
+            .put_with_ttl(&key, "handled", HANDLED_EVENT_TTL)
+            .await
+        {
+            warn!("Failed to remember event {}: {}", event.webhook_event_id, e);
+        }
+    }
+
+    /// Answers a sticker: echoes a sticker that another group member just
+    /// sent, then tries the configured sticker replies, then (in DMs) the
+    /// sticker oracle.
+    pub async fn send_sticker_reply(
+        &self,
+        target: &ReplyTarget<'_>,
+        message_id: &str,
+        sticker: &StickerRef,
+        keywords: &[String],
+        source: &Source,
+    ) -> Result<()> {
+        let user_id = source.user_id.as_deref().unwrap_or("unknown");
+        let group_id = source.group_chat_id();
 
-    let client = reqwest::Client::new();
+        if group_id.is_some() && !self.is_replies_enabled().await {
+            return Ok(());
+        }
 
-    let response = client
-        .post("https://api.line.me/v2/bot/message/push")
-        .header("Authorization", format!("Bearer {}", channel_access_token))
-        .header("Content-Type", "application/json")
-        .json(&push_request)
-        .send()
-        .await?;
+        let repeat_group_id = group_id.filter(|_| !self.disable_repeat_detection);
+        if let Some(group_id) = repeat_group_id {
+            let repeated = self.is_repeated_sticker(sticker, user_id, group_id).await;
+            self.record_sticker(group_id, user_id, message_id, sticker)
+                .await;
+            if repeated {
+                info!(
+                    "Repeated sticker detected in group {}: {}/{}",
+                    group_id, sticker.package_id, sticker.sticker_id
+                );
+                return self
+                    .send_line_messages(target, vec![sticker.to_message()])
+                    .await;
+            }
+        }
 
-    if !response.status().is_success() {
-        let error_text = response.text().await?;
-        return Err(format!("LINE API push error: {}", error_text).into());
+        let stickers = StickerConfig::from_env(self.env);
+        let reply = match stickers.reply_for(sticker, keywords) {
+            Some(reply) => reply,
+            None if group_id.is_none() => {
+                let yes = create_reply(user_id, message_id) == "yes";
+                let Some(reply) = stickers.oracle_reply(yes) else {
+                    return Ok(());
+                };
+                reply
+            }
+            None => return Ok(()),
+        };
+        self.send_line_messages(target, vec![reply.to_message()])
+            .await
     }
 
-    info!("Push message sent to {}: {}", to, text);
-    Ok(())
+    pub async fn send_reply(
+        &self,
+        target: &ReplyTarget<'_>,
+        message_id: &str,
+        text: &str,
+        source: &Source,
+    ) -> Result<()> {
+        let user_id = source.user_id.as_deref().unwrap_or("unknown");
+        let group_chat_id = source.group_chat_id();
+        let is_group_chat = group_chat_id.is_some();
+
+        let trimmed_text = text.trim().to_lowercase();
+        let is_dolphin_message = trimmed_text.starts_with("@dolphin");
+        let is_off_command = trimmed_text.starts_with("@off");
+        let is_on_command = trimmed_text.starts_with("@on");
+        let is_followers_command = trimmed_text.starts_with("@followers");
+        let is_groups_command = trimmed_text.starts_with("@groups");
+        let is_welcome_command = trimmed_text.starts_with("@welcome");
+        let is_farewell_command = trimmed_text.starts_with("@farewell");
+        let is_style_command = trimmed_text.starts_with("@style");
+
+        // Check for @all+XXXX pattern (send to specific group by last 4 digits) or "@all"
+        let all_plus_pattern = Regex::new(r"^@all\+(\w{4})").unwrap();
+        let target_group_digits = all_plus_pattern
+            .captures(&trimmed_text)
+            .and_then(|cap| cap.get(1))
+            .map(|m| m.as_str().to_string());
+        let is_all_plus_message = target_group_digits.is_some();
+        let is_all_message = target_group_digits.is_none() && trimmed_text.starts_with("@all");
+        let is_command = is_command(&trimmed_text);
+
+        // Handle @off and @on commands from authorized user
+        if (is_off_command || is_on_command) && self.is_admin(user_id) {
+            let enable = is_on_command;
+            if self.set_replies_enabled(enable).await.is_ok() {
+                let status = if enable { "enabled" } else { "disabled" };
+                let reply_text = format!("🔧 Replies have been {}", status);
+                self.send_line_reply(target, &reply_text).await?;
+                info!("Reply status changed to {} by user {}", status, user_id);
+            } else {
+                error!("Failed to save reply state");
+                let reply_text = "❌ Failed to change reply status";
+                self.send_line_reply(target, reply_text).await?;
+            }
+            return Ok(());
+        }
+
+        // Handle @followers and @groups from authorized user
+        if (is_followers_command || is_groups_command) && self.is_admin(user_id) {
+            let reply_text = if is_followers_command {
+                self.followers_report().await
+            } else {
+                self.groups_report().await
+            };
+            self.send_line_reply(target, &reply_text).await?;
+            return Ok(());
+        }
+
+        // Handle @welcome and @farewell template edits from authorized user
+        if (is_welcome_command || is_farewell_command) && self.is_admin(user_id) {
+            let command = if is_welcome_command {
+                "@welcome"
+            } else {
+                "@farewell"
+            };
+            let template = strip_command(text, command);
+            let reply_text = match group_chat_id {
+                Some(group_id) => {
+                    self.edit_group_template(group_id, is_welcome_command, template)
+                        .await
+                }
+                None => "❌ Templates can only be changed inside a group or room".to_string(),
+            };
+            self.send_line_reply(target, &reply_text).await?;
+            return Ok(());
+        }
+
+        // Handle @style answer style changes from authorized user
+        if is_style_command && self.is_admin(user_id) {
+            let reply_text = match group_chat_id {
+                Some(group_id) => {
+                    self.edit_answer_style(group_id, strip_command(text, "@style"))
+                        .await
+                }
+                None => {
+                    "❌ The answer style can only be changed inside a group or room".to_string()
+                }
+            };
+            self.send_line_reply(target, &reply_text).await?;
+            return Ok(());
+        }
+
+        // Check if replies are enabled
+        if !self.is_replies_enabled().await && is_group_chat {
+            info!(
+                "Replies are disabled, ignoring message from user {}",
+                user_id
+            );
+            return Ok(());
+        }
+
+        // Check for repeated messages in group and room conversations
+        let repeat_group_id = group_chat_id.filter(|_| !self.disable_repeat_detection);
+        if let Some(group_id) = repeat_group_id {
+            // Skip repeated message check for commands (@dolphin, @all, @on, @off)
+            if !is_command {
+                if let Some(repeated_reply) =
+                    self.check_repeated_message(text, user_id, group_id).await
+                {
+                    self.record_message(group_id, user_id, message_id, text)
+                        .await;
+
+                    // Reply with the previous message in lowercase
+                    self.send_line_reply(target, &repeated_reply).await?;
+                    info!(
+                        "Repeated message detected in group {}: {}",
+                        group_id, repeated_reply
+                    );
+                    return Ok(());
+                }
+            }
+        }
+
+        if !is_dolphin_message && !is_all_message && !is_all_plus_message {
+            if is_group_chat {
+                // Ignore messages that aren't commands in group chats, but remember them
+                if let Some(group_id) = repeat_group_id {
+                    self.record_message(group_id, user_id, message_id, text)
+                        .await;
+                }
+                return Ok(());
+            }
+
+            // For direct messages, reply to all messages
+            let reply_text = create_reply(user_id, text);
+            self.send_answer(target, text, &reply_text, source).await?;
+            info!("Reply to user {}: {}", user_id, text);
+            return Ok(());
+        }
+
+        let broadcast_configs = BroadcastConfig::from_env(self.env);
+        let (message_content, is_broadcast, authorized_broadcast, target_group_id) =
+            if let Some(digits) = &target_group_digits {
+                // Extract the message content after "@all+XXXX"
+                let content = strip_command(text, &format!("@all+{}", digits));
+
+                // For @all+XXXX, find the group that ends with XXXX from all configured groups
+                let target_group = broadcast_configs
+                    .iter()
+                    .find(|config| config.target_group_id.ends_with(digits.as_str()))
+                    .map(|config| config.target_group_id.clone());
+
+                let authorized = target_group.is_some();
+
+                (content.to_string(), true, authorized, target_group)
+            } else if is_all_message {
+                // Extract the message content after "@all"
+                let content = strip_command(text, "@all");
+
+                // Check if user is authorized to broadcast
+                let authorized = self.is_admin(user_id);
+
+                (content.to_string(), true, authorized, None)
+            } else {
+                // Extract the message content after "@dolphin"
+                let content = strip_command(text, "@dolphin");
+                (content.to_string(), false, false, None)
+            };
+
+        if message_content.is_empty() {
+            return Ok(());
+        }
+
+        let reply_text = self
+            .create_response_msg(
+                user_id,
+                &message_content,
+                is_group_chat,
+                is_broadcast,
+                authorized_broadcast,
+                &target_group_id,
+                &target_group_digits,
+                &broadcast_configs,
+            )
+            .await;
+
+        if is_broadcast {
+            let reply = answer_text(target, source, &reply_text);
+            self.send_line_messages(target, vec![reply]).await?;
+        } else {
+            self.send_answer(target, &message_content, &reply_text, source)
+                .await?;
+        }
+        let group_id = group_chat_id.unwrap_or("unknown");
+        info!(
+            "Reply sent (group_id:{}, broadcast:{}, user_id:{}): {}",
+            group_id,
+            is_broadcast,
+            user_id.get(0..4).unwrap_or(user_id),
+            message_content
+        );
+
+        // Update message history for group messages
+        if let Some(group_id) = repeat_group_id {
+            self.record_message(group_id, user_id, message_id, text)
+                .await;
+        }
+
+        Ok(())
+    }
+
+    /// Creates a response message based on the message context
+    #[allow(clippy::too_many_arguments)]
+    async fn create_response_msg(
+        &self,
+        user_id: &str,
+        message_content: &str,
+        is_group_chat: bool,
+        is_broadcast: bool,
+        authorized_broadcast: bool,
+        target_group_id: &Option<String>,
+        target_group_digits: &Option<String>,
+        broadcast_configs: &[BroadcastConfig],
+    ) -> String {
+        if is_group_chat {
+            // For @dolphin and @all messages, use the standard checksum logic
+            return create_reply(user_id, message_content);
+        }
+
+        if authorized_broadcast {
+            // Send broadcast message to target group
+            let target_group = if let Some(group_id) = target_group_id {
+                // @all+XXXX case - use the found group
+                group_id.clone()
+            } else {
+                // @all case - use user's configured group
+                BroadcastConfig::find_by_user_id(broadcast_configs, user_id)
+                    .map(|config| config.target_group_id.clone())
+                    .unwrap_or_default()
+            };
+
+            if target_group.is_empty() {
+                return "❌ Broadcast configuration not found".to_string();
+            }
+
+            match self.send_push_message(&target_group, message_content).await {
+                Ok(()) => format!(
+                    "📢 Broadcast message sent to group: \"{}\"",
+                    message_content
+                ),
+                Err(e) => {
+                    error!("Failed to send broadcast message: {}", e);
+                    format!("❌ Failed to broadcast message: \"{}\"", message_content)
+                }
+            }
+        } else if is_broadcast {
+            // User not authorized to broadcast
+            match target_group_digits {
+                Some(digits) => format!("❌ No group found with last 4 digits: {}", digits),
+                None => "❌ You are not authorized to use @all broadcasts".to_string(),
+            }
+        } else {
+            create_reply(user_id, message_content)
+        }
+    }
+
+    async fn is_replies_enabled(&self) -> bool {
+        match self.store.get("enabled").await {
+            Ok(Some(content)) => content.trim() == "enabled",
+            _ => true, // Default to enabled
+        }
+    }
+
+    async fn set_replies_enabled(&self, enabled: bool) -> Result<()> {
+        let state = if enabled { "enabled" } else { "disabled" };
+        self.store.put("enabled", state).await
+    }
+
+    /// Whether the user may run admin commands, i.e. has a broadcast target
+    /// in `DOLPHIN_USER_TO_GROUP*`.
+    fn is_admin(&self, user_id: &str) -> bool {
+        let broadcast_configs = BroadcastConfig::from_env(self.env);
+        BroadcastConfig::find_by_user_id(&broadcast_configs, user_id).is_some()
+    }
+
+    async fn followers_report(&self) -> String {
+        match self.store.keys(FOLLOWER_PREFIX).await {
+            Ok(followers) => format!("👥 Followers: {}", followers.len()),
+            Err(e) => {
+                error!("Failed to load followers: {}", e);
+                "❌ Failed to count followers".to_string()
+            }
+        }
+    }
+
+    async fn groups_report(&self) -> String {
+        let groups = match self.get_groups().await {
+            Ok(groups) => groups,
+            Err(e) => {
+                error!("Failed to load groups: {}", e);
+                return "❌ Failed to list groups".to_string();
+            }
+        };
+        if groups.is_empty() {
+            return "📋 No known groups yet".to_string();
+        }
+
+        let mut lines = vec![format!("📋 Known groups: {}", groups.len())];
+        for (group_id, record) in &groups {
+            lines.push(format!(
+                "• {} (first seen {}, last active {})",
+                group_id,
+                format_date(record.first_seen),
+                format_date(record.last_active)
+            ));
+        }
+        lines.join("\n")
+    }
+
+    async fn get_group_templates(&self, group_id: &str) -> GroupTemplates {
+        match self.store.get_json(&templates_key(group_id)).await {
+            Ok(Some(templates)) => templates,
+            _ => GroupTemplates::default(),
+        }
+    }
+
+    /// Shows, replaces (`<template>`) or turns off (`off`) a group's welcome
+    /// or farewell template. Returns the confirmation to send back.
+    async fn edit_group_template(&self, group_id: &str, welcome: bool, template: &str) -> String {
+        let mut templates = self.get_group_templates(group_id).await;
+        let name = if welcome { "Welcome" } else { "Farewell" };
+
+        if template.is_empty() {
+            let current = if welcome {
+                templates.welcome()
+            } else {
+                templates.farewell.as_deref().unwrap_or_default()
+            };
+            return if current.is_empty() {
+                format!("📝 {} message is off", name)
+            } else {
+                format!("📝 {} message: {}", name, current)
+            };
+        }
+
+        let (new_template, reply_text) = if template.eq_ignore_ascii_case("off") {
+            (String::new(), format!("🔧 {} message turned off", name))
+        } else {
+            let reply_text = format!("🔧 {} message set to: {}", name, template);
+            (template.to_string(), reply_text)
+        };
+        if welcome {
+            templates.welcome = Some(new_template);
+        } else {
+            templates.farewell = Some(new_template);
+        }
+
+        match self
+            .store
+            .put_json(&templates_key(group_id), &templates)
+            .await
+        {
+            Ok(()) => reply_text,
+            Err(e) => {
+                error!("Failed to save templates for {}: {}", group_id, e);
+                format!("❌ Failed to change the {} message", name.to_lowercase())
+            }
+        }
+    }
+
+    /// Shows or changes a group's answer style: `text`, `flex`, or
+    /// `default` to follow `DOLPHIN_ANSWER_STYLE` again.
+    async fn edit_answer_style(&self, group_id: &str, value: &str) -> String {
+        if value.is_empty() {
+            let style = self.group_answer_style(group_id).await;
+            return format!("🎨 Answer style: {}", style.as_str());
+        }
+
+        let key = answer_style_key(group_id);
+        let (result, reply_text) = if value.eq_ignore_ascii_case("default") {
+            let reply_text = format!(
+                "🔧 Answer style reset to the default ({})",
+                self.answer_style.as_str()
+            );
+            (self.store.delete(&key).await, reply_text)
+        } else if let Some(style) = AnswerStyle::parse(value) {
+            let reply_text = format!("🔧 Answer style set to {}", style.as_str());
+            (self.store.put(&key, style.as_str()).await, reply_text)
+        } else {
+            return format!(
+                "❌ Unknown answer style: {} (use text, flex or default)",
+                value
+            );
+        };
+
+        match result {
+            Ok(()) => reply_text,
+            Err(e) => {
+                error!("Failed to save answer style for {}: {}", group_id, e);
+                "❌ Failed to change the answer style".to_string()
+            }
+        }
+    }
+
+    /// The known groups with their records, ordered by group ID.
+    async fn get_groups(&self) -> Result<Vec<(String, GroupRecord)>> {
+        let mut groups = Vec::new();
+        for key in self.store.keys(GROUP_PREFIX).await? {
+            // Skip groups that left between listing and reading
+            if let Some(record) = self.store.get_json(&key).await? {
+                groups.push((key[GROUP_PREFIX.len()..].to_string(), record));
+            }
+        }
+        Ok(groups)
+    }
+
+    /// Registers a group, or refreshes its last activity, ignoring storage
+    /// errors. Activity is only written once per `GROUP_ACTIVITY_RESOLUTION_MS`
+    /// to keep busy groups from rewriting their record on every message.
+    async fn touch_group(&self, group_id: &str, timestamp: i64) {
+        let key = group_key(group_id);
+        let record = match self.store.get_json::<GroupRecord>(&key).await {
+            Ok(Some(record)) if timestamp - record.last_active < GROUP_ACTIVITY_RESOLUTION_MS => {
+                return;
+            }
+            Ok(Some(record)) => GroupRecord {
+                last_active: timestamp,
+                ..record
+            },
+            Ok(None) => {
+                info!("Registering group: {}", group_id);
+                GroupRecord {
+                    first_seen: timestamp,
+                    last_active: timestamp,
+                }
+            }
+            Err(e) => {
+                error!("Failed to load group {}: {}", group_id, e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.store.put_json(&key, &record).await {
+            error!("Failed to save group {}: {}", group_id, e);
+        }
+    }
+
+    async fn unregister_group(&self, group_id: &str) -> Result<()> {
+        self.store.delete(&group_key(group_id)).await
+    }
+
+    async fn add_follower(&self, user_id: &str, followed_at: i64) -> Result<()> {
+        let record = FollowerRecord { followed_at };
+        self.store.put_json(&follower_key(user_id), &record).await
+    }
+
+    /// Removes a user from the followers, which deletes their per-user data.
+    async fn remove_follower(&self, user_id: &str) -> Result<()> {
+        self.store.delete(&follower_key(user_id)).await
+    }
+
+    async fn get_message_history(&self, group_id: &str) -> MessageHistory {
+        let key = format!("msg_history:{}", group_id);
+        match self.store.get_json(&key).await {
+            Ok(Some(history)) => history,
+            _ => MessageHistory::new(),
+        }
+    }
+
+    async fn save_message_history(&self, group_id: &str, history: &MessageHistory) -> Result<()> {
+        let key = format!("msg_history:{}", group_id);
+        self.store.put_json(&key, history).await
+    }
+
+    /// Appends a message to the group's history, ignoring storage errors.
+    async fn record_message(&self, group_id: &str, user_id: &str, message_id: &str, text: &str) {
+        let mut history = self.get_message_history(group_id).await;
+        history.add_message(
+            user_id.to_string(),
+            message_id.to_string(),
+            text.to_string(),
+        );
+        let _ = self.save_message_history(group_id, &history).await;
+    }
+
+    async fn check_repeated_message(
+        &self,
+        current_message: &str,
+        current_user_id: &str,
+        group_id: &str,
+    ) -> Option<String> {
+        let history = self.get_message_history(group_id).await;
+
+        // Get the previous message (the last entry in history)
+        let last_entry = history.get_last_entry()?;
+
+        // Only trigger repeat if the sender is different
+        if last_entry.user_id == current_user_id || last_entry.sticker.is_some() {
+            return None;
+        }
+
+        // Check if current message has previous message (case-insensitive)
+        let current_lower = current_message.to_lowercase();
+        let previous_lower = last_entry.message.to_lowercase();
+
+        if current_lower.starts_with(&previous_lower) {
+            // Return the previous message in lowercase
+            Some(previous_lower)
+        } else {
+            None
+        }
+    }
+
+    /// Appends a sticker to the group's history, ignoring storage errors.
+    async fn record_sticker(
+        &self,
+        group_id: &str,
+        user_id: &str,
+        message_id: &str,
+        sticker: &StickerRef,
+    ) {
+        let mut history = self.get_message_history(group_id).await;
+        history.add_sticker(user_id.to_string(), message_id.to_string(), sticker.clone());
+        let _ = self.save_message_history(group_id, &history).await;
+    }
+
+    /// Returns true when another user sent the same sticker just before.
+    async fn is_repeated_sticker(
+        &self,
+        sticker: &StickerRef,
+        current_user_id: &str,
+        group_id: &str,
+    ) -> bool {
+        let history = self.get_message_history(group_id).await;
+        history.get_last_entry().is_some_and(|last_entry| {
+            last_entry.user_id != current_user_id && last_entry.sticker.as_ref() == Some(sticker)
+        })
+    }
+
+    async fn send_line_reply(&self, target: &ReplyTarget<'_>, reply_text: &str) -> Result<()> {
+        self.send_line_messages(target, vec![ReplyMessage::text(reply_text)])
+            .await
+    }
+
+    /// Replies with the messages. When they don't fit in one reply, the
+    /// rest follow as pushes to the chat, see [`ReplyBuilder`].
+    async fn send_line_messages(
+        &self,
+        target: &ReplyTarget<'_>,
+        messages: Vec<ReplyMessage>,
+    ) -> Result<()> {
+        let mut batches = ReplyBuilder::new().messages(messages).build().into_iter();
+        let Some(messages) = batches.next() else {
+            return Ok(());
+        };
+
+        // Validate reply token
+        let result = match target.reply_token.filter(|token| !token.trim().is_empty()) {
+            Some(reply_token) => self.line.reply(reply_token, messages.clone()).await,
+            None => Err("Reply token cannot be empty".into()),
+        };
+
+        match (result, target.push_fallback) {
+            (Err(e), Some(to)) => {
+                warn!("Reply failed ({}), pushing to {} instead", e, to);
+                self.line.push(to, messages).await?;
+            }
+            (result, _) => result?,
+        }
+
+        let overflow: Vec<_> = batches.collect();
+        if overflow.is_empty() {
+            return Ok(());
+        }
+        let Some(to) = target.push_fallback.or(target.chat_id) else {
+            warn!(
+                "No chat to push {} more messages to",
+                overflow.concat().len()
+            );
+            return Ok(());
+        };
+        for messages in overflow {
+            self.line.push(to, messages).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_push_message(&self, to: &str, text: &str) -> Result<()> {
+        for messages in ReplyBuilder::new().text(text).build() {
+            self.line.push(to, messages).await?;
+        }
+        info!("Push message sent to {}: {}", to, text);
+        Ok(())
+    }
+}
+
+/// An answer as plain text. In busy group chats it quotes the question it
+/// belongs to.
+fn answer_text(target: &ReplyTarget<'_>, source: &Source, text: &str) -> ReplyMessage {
+    match target
+        .quote_token
+        .filter(|_| source.group_chat_id().is_some())
+    {
+        Some(quote_token) => ReplyMessage::quoted_text(text, quote_token),
+        None => ReplyMessage::text(text),
+    }
+}
+
+/// The longest text of a message action and data of a postback action.
+const MAX_ACTION_TEXT_CHARS: usize = 300;
+
+/// Quick reply buttons under an answer: ask the same question again, ask why,
+/// or flip a coin. Buttons too long for LINE with a long question are left out.
+fn answer_buttons(question: &str, answer: &str) -> QuickReply {
+    let mut actions = Vec::new();
+    let fits = |text: &str| !question.is_empty() && text.chars().count() <= MAX_ACTION_TEXT_CHARS;
+
+    let ask_again = format!("@dolphin {}", question);
+    if fits(&ask_again) {
+        actions.push(Action::message("Ask again", &ask_again));
+    }
+    let why = PostbackAction::Why {
+        question: question.to_string(),
+        answer: answer.to_string(),
+    };
+    let why = why.to_data().encode();
+    if fits(&why) {
+        actions.push(Action::postback("Why?", &why, Some("Why?")));
+    }
+    let flip = PostbackAction::Flip.to_data().encode();
+    actions.push(Action::postback("Flip a coin", &flip, Some("Flip a coin")));
+
+    QuickReply::new(actions)
+}
+
+/// The dolphin's reasons, picked by question so the same question always
+/// gets the same one.
+const REASONS: [&str; 4] = [
+    "the tides said so",
+    "the other dolphins agreed",
+    "the bubbles never lie",
+    "the ocean doesn't explain itself",
+];
+
+/// Explains the answer the dolphin gave to a question.
+fn explain_answer(question: &str, answer: &str) -> String {
+    let sum: usize = question.chars().map(|c| c as usize).sum();
+    let reason = REASONS[sum % REASONS.len()];
+    format!("🐬 {}, because {}", answer, reason)
+}
+
+fn flip_coin(timestamp: i64) -> &'static str {
+    if timestamp % 2 == 0 {
+        "🪙 Heads!"
+    } else {
+        "🪙 Tails!"
+    }
+}
+
+/// Returns the trimmed text after a case-insensitive command prefix, or an
+/// empty string when the text doesn't start with the command.
+fn strip_command<'t>(text: &'t str, command: &str) -> &'t str {
+    let text = text.trim();
+    match text.get(..command.len()) {
+        Some(prefix) if prefix.eq_ignore_ascii_case(command) => text[command.len()..].trim(),
+        _ => "",
+    }
+}
+
+/// Creates a reply based on checksum of user ID and message
+/// Returns "yes" if the sum is even, "no" if odd
+/// Special case: if message contains both "buy" and "nuclear", always returns "yes"
+pub fn create_reply(user_id: &str, message: &str) -> String {
+    let lower_message = message.to_lowercase();
+
+    // Special case: if message contains both "buy" and "nuclear", always return "yes"
+    if lower_message.contains("buy") && lower_message.contains("nuclear") {
+        return "yes".to_string();
+    }
+
+    // Calculate checksum: sum of all ASCII values in user_id + message
+    let user_sum: u32 = user_id.chars().map(|c| c as u32).sum();
+    let message_sum: u32 = message.chars().map(|c| c as u32).sum();
+    let total_sum = user_sum + message_sum;
+
+    // Return "yes" if even, "no" if odd
+    if total_sum.is_multiple_of(2) {
+        "yes".to_string()
+    } else {
+        "no".to_string()
+    }
+}
+
+/// Formats a timestamp in ms since the Unix epoch as a UTC `YYYY-MM-DD` date.
+fn format_date(timestamp_ms: i64) -> String {
+    // Civil-from-days conversion for the proleptic Gregorian calendar
+    let days = timestamp_ms.div_euclid(86_400_000);
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::line::{LineCall, RecordingLineClient};
+    use crate::state::MemoryStore;
+
+    #[test]
+    fn test_verify_signature_valid() {
+        let body = b"{\"destination\":\"abc\",\"events\":[]}";
+        let secret = "channel_secret";
+
+        // Calculate expected signature manually to verify
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        let expected = general_purpose::STANDARD.encode(mac.finalize().into_bytes());
+
+        let result = verify_signature(body, &expected, secret);
+        assert!(result);
+    }
+
+    #[test]
+    fn test_verify_signature_invalid() {
+        let body = b"{\"destination\":\"abc\",\"events\":[]}";
+        let secret = "channel_secret";
+        let invalid_signature = "invalid_sig_base64";
+
+        let result = verify_signature(body, invalid_signature, secret);
+        assert!(!result);
+        assert!(!verify_signature(
+            body,
+            &sign_body(body, "other_secret"),
+            secret
+        ));
+    }
+
+    #[test]
+    fn test_verify_signature_malformed() {
+        let body = b"{\"destination\":\"abc\",\"events\":[]}";
+        let secret = "channel_secret";
+        let signature = sign_body(body, secret);
+
+        assert!(!verify_signature(body, "", secret));
+        assert!(!verify_signature(body, "not base64!", secret));
+        assert!(!verify_signature(body, &signature[..20], secret));
+        assert!(!verify_signature(body, &format!("{}AA", signature), secret));
+    }
+
+    #[test]
+    fn test_render_welcome_mentions_members() {
+        let message = render_welcome("Hi {name} {{not me}}", &["U1", "U2"]);
+
+        let ReplyMessage::TextV2 {
+            text, substitution, ..
+        } = message
+        else {
+            panic!("expected a textV2 message, got {:?}", message);
+        };
+        assert_eq!(text, "Hi {user1}, {user2} {{{{not me}}}}");
+        assert_eq!(
+            substitution.get("user2"),
+            Some(&Substitution::mention_user("U2"))
+        );
+        assert_eq!(render_welcome("Hi!", &["U1"]), ReplyMessage::text("Hi!"));
+    }
+
+    #[test]
+    fn test_strip_command() {
+        assert_eq!(
+            strip_command("  @Dolphin  will it rain? ", "@dolphin"),
+            "will it rain?"
+        );
+        assert_eq!(strip_command("@ALL+1234 lunch", "@all+1234"), "lunch");
+        assert_eq!(strip_command("@dolphin", "@dolphin"), "");
+        assert_eq!(strip_command("@dolp🐬", "@dolphin"), "");
+    }
+
+    #[test]
+    fn test_answer_buttons_fit_line_limits() {
+        let labels = |quick_reply: QuickReply| -> Vec<String> {
+            let value = serde_json::to_value(quick_reply).unwrap();
+            value["items"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["action"]["label"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        assert_eq!(
+            labels(answer_buttons("will it rain?", "no")),
+            vec!["Ask again", "Why?", "Flip a coin"]
+        );
+        assert_eq!(
+            labels(answer_buttons(&"?".repeat(300), "no")),
+            vec!["Flip a coin"]
+        );
+        assert_eq!(labels(answer_buttons("", "no")), vec!["Flip a coin"]);
+    }
+
+    #[test]
+    fn test_flip_coin() {
+        assert_eq!(flip_coin(1_700_000_000_000), "🪙 Heads!");
+        assert_eq!(flip_coin(1_700_000_000_001), "🪙 Tails!");
+    }
+
+    #[test]
+    fn test_explain_answer_is_stable() {
+        let explanation = explain_answer("buy nuclear", "yes");
+        assert!(explanation.starts_with("🐬 yes, because "));
+        assert_eq!(explain_answer("buy nuclear", "yes"), explanation);
+        assert!(REASONS.iter().any(|reason| explanation.ends_with(reason)));
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(1_700_000_000_000), "2023-11-14");
+        assert_eq!(format_date(951_782_400_000), "2000-02-29");
+    }
+
+    fn source(user_id: &str, group_id: Option<&str>) -> Source {
+        Source {
+            source_type: if group_id.is_some() { "group" } else { "user" }.to_string(),
+            user_id: Some(user_id.to_string()),
+            group_id: group_id.map(str::to_string),
+            room_id: None,
+        }
+    }
+
+    fn reply(text: &str) -> LineCall {
+        LineCall::Reply {
+            reply_token: "token".to_string(),
+            messages: vec![ReplyMessage::text(text)],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handle_webhook_checks_signature() {
+        let env = HashMap::from([(
+            "LINE_CHANNEL_SECRET".to_string(),
+            "channel_secret".to_string(),
+        )]);
+        let store = MemoryStore::new();
+        let line = RecordingLineClient::new();
+        let dolphin = Dolphin::new(&env, &store, &line);
+        let body = b"{\"destination\":\"abc\",\"events\":[]}";
+
+        let valid = sign_body(body, "channel_secret");
+        assert_eq!(dolphin.handle_webhook(body, Some(&valid)).await, Ok(()));
+
+        let forged = sign_body(body, "other_secret");
+        assert_eq!(
+            dolphin.handle_webhook(body, Some(&forged)).await,
+            Err(WebhookError::Unauthorized)
+        );
+        assert_eq!(
+            dolphin.handle_webhook(body, None).await,
+            Err(WebhookError::Unauthorized)
+        );
+
+        let bad_body = b"not json";
+        let signature = sign_body(bad_body, "channel_secret");
+        assert_eq!(
+            dolphin.handle_webhook(bad_body, Some(&signature)).await,
+            Err(WebhookError::BadRequest)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_reply_answers_dms_and_dolphin_questions() {
+        let env = HashMap::new();
+        let store = MemoryStore::new();
+        let line = RecordingLineClient::new();
+        let dolphin = Dolphin::new(&env, &store, &line);
+        let token = ReplyTarget::token("token");
+
+        let dm = source("U1234", None);
+        dolphin
+            .send_reply(&token, "m1", "hello", &dm)
+            .await
+            .unwrap();
+
+        let group = source("U1234", Some("C5678"));
+        dolphin
+            .send_reply(&token, "m2", "chit chat", &group)
+            .await
+            .unwrap();
+        dolphin
+            .send_reply(&token, "m3", "@dolphin should I buy nuclear?", &group)
+            .await
+            .unwrap();
+
+        let answer = |text: &str, question: &str| LineCall::Reply {
+            reply_token: "token".to_string(),
+            messages: vec![
+                ReplyMessage::text(text).with_quick_reply(answer_buttons(question, text))
+            ],
+        };
+        assert_eq!(
+            line.calls(),
+            vec![
+                answer(&create_reply("U1234", "hello"), "hello"),
+                answer("yes", "should I buy nuclear?"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_only_multi_person_chat_answers_are_quoted() {
+        let env = HashMap::from([("DISABLE_QUICK_REPLIES".to_string(), "true".to_string())]);
+        let store = MemoryStore::new();
+        let line = RecordingLineClient::new();
+        let dolphin = Dolphin::new(&env, &store, &line);
+        let target = ReplyTarget {
+            quote_token: Some("q1"),
+            ..ReplyTarget::token("token")
+        };
+
+        let question = "@dolphin should I buy nuclear?";
+        dolphin
+            .send_reply(&target, "m1", question, &source("U1234", None))
+            .await
+            .unwrap();
+        dolphin
+            .send_reply(&target, "m2", question, &source("U1234", Some("C5678")))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            line.calls(),
+            vec![
+                reply("yes"),
+                LineCall::Reply {
+                    reply_token: "token".to_string(),
+                    messages: vec![ReplyMessage::quoted_text("yes", "q1")],
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_flex_answers_name_the_asker() {
+        let env = HashMap::from([("DOLPHIN_ANSWER_STYLE".to_string(), "flex".to_string())]);
+        let store = MemoryStore::new();
+        let line = RecordingLineClient::new();
+        line.add_profile("U1234", "Nemo");
+        let dolphin = Dolphin::new(&env, &store, &line);
+        let question = "@dolphin should I buy nuclear?";
+
+        dolphin
+            .send_reply(
+                &ReplyTarget::token("token"),
+                "m1",
+                question,
+                &source("U1234", None),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            line.calls(),
+            vec![LineCall::Reply {
+                reply_token: "token".to_string(),
+                messages: vec![flex::oracle_bubble(
+                    "should I buy nuclear?",
+                    "yes",
+                    Some("Nemo"),
+                    None
+                )
+                .with_quick_reply(answer_buttons("should I buy nuclear?", "yes"))],
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_off_command_silences_groups() {
+        let env = HashMap::from([(
+            "DOLPHIN_USER_TO_GROUP1".to_string(),
+            "Uadmin:C5678".to_string(),
+        )]);
+        let store = MemoryStore::new();
+        let line = RecordingLineClient::new();
+        let dolphin = Dolphin::new(&env, &store, &line);
+        let token = ReplyTarget::token("token");
+
+        let group = source("Uadmin", Some("C5678"));
+        dolphin
+            .send_reply(&token, "m4", "@off", &group)
+            .await
+            .unwrap();
+        dolphin
+            .send_reply(&token, "m5", "@dolphin anyone there?", &group)
+            .await
+            .unwrap();
+
+        assert_eq!(line.calls(), vec![reply("🔧 Replies have been disabled")]);
+        assert_eq!(
+            store.get("enabled").await.unwrap().as_deref(),
+            Some("disabled")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_all_broadcasts_to_configured_group() {
+        let env = HashMap::from([(
+            "DOLPHIN_USER_TO_GROUP1".to_string(),
+            "Uadmin:C5678".to_string(),
+        )]);
+        let store = MemoryStore::new();
+        let line = RecordingLineClient::new();
+        let dolphin = Dolphin::new(&env, &store, &line);
+        let token = ReplyTarget::token("token");
+
+        let dm = source("Uadmin", None);
+        dolphin
+            .send_reply(&token, "m6", "@all lunch!", &dm)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            line.calls(),
+            vec![
+                LineCall::Push {
+                    to: "C5678".to_string(),
+                    messages: vec![ReplyMessage::text("lunch!")],
+                },
+                reply("📢 Broadcast message sent to group: \"lunch!\""),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_long_broadcast_confirmation_is_split() {
+        let env = HashMap::from([(
+            "DOLPHIN_USER_TO_GROUP1".to_string(),
+            "Uadmin:C5678".to_string(),
+        )]);
+        let store = MemoryStore::new();
+        let line = RecordingLineClient::new();
+        let dolphin = Dolphin::new(&env, &store, &line);
+        let announcement = "🐬".repeat(2490);
+
+        dolphin
+            .send_reply(
+                &ReplyTarget::token("token"),
+                "m7",
+                &format!("@all {}", announcement),
+                &source("Uadmin", None),
+            )
+            .await
+            .unwrap();
+
+        let calls = line.calls();
+        assert_eq!(
+            calls[0],
+            LineCall::Push {
+                to: "C5678".to_string(),
+                messages: vec![ReplyMessage::text(&announcement)],
+            }
+        );
+        let LineCall::Reply { messages, .. } = &calls[1] else {
+            panic!("expected a reply, got {:?}", calls[1]);
+        };
+        let parts: Vec<&str> = messages
+            .iter()
+            .map(|message| match message {
+                ReplyMessage::Text { text, .. } => text.as_str(),
+                _ => panic!("expected text, got {:?}", message),
+            })
+            .collect();
+        assert_eq!(parts.len(), 2);
+        assert!(parts.iter().all(|part| part.encode_utf16().count() <= 5000));
+        assert_eq!(
+            parts.concat(),
+            format!("📢 Broadcast message sent to group: \"{}\"", announcement)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejected_reply_falls_back_to_push() {
+        let env = HashMap::from([("DISABLE_QUICK_REPLIES".to_string(), "true".to_string())]);
+        let store = MemoryStore::new();
+        let line = RecordingLineClient::new();
+        let dolphin = Dolphin::new(&env, &store, &line);
+        let target = ReplyTarget {
+            reply_token: Some("stale"),
+            push_fallback: Some("C5678"),
+            ..Default::default()
+        };
+
+        line.reject_replies(true);
+        dolphin
+            .send_reply(
+                &target,
+                "m1",
+                "@dolphin should I buy nuclear?",
+                &source("U1234", Some("C5678")),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            line.calls(),
+            vec![LineCall::Push {
+                to: "C5678".to_string(),
+                messages: vec![ReplyMessage::text("yes")],
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unfollow_deletes_user_data() {
+        let env = HashMap::new();
+        let store = MemoryStore::new();
+        let line = RecordingLineClient::new();
+        let dolphin = Dolphin::new(&env, &store, &line);
+
+        dolphin.add_follower("U1234", 1700000000000).await.unwrap();
+        dolphin.add_follower("U5678", 1700000000000).await.unwrap();
+        assert!(store.get("follower:U1234").await.unwrap().is_some());
+        assert_eq!(dolphin.followers_report().await, "👥 Followers: 2");
+
+        dolphin.remove_follower("U1234").await.unwrap();
+        assert_eq!(store.get("follower:U1234").await.unwrap(), None);
+        assert_eq!(dolphin.followers_report().await, "👥 Followers: 1");
+    }
 }
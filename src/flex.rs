@@ -0,0 +1,161 @@
+use serde_json::json;
+
+use crate::dolphin::Secrets;
+use crate::types::ReplyMessage;
+
+/// How the dolphin shows its yes or no. `DOLPHIN_ANSWER_STYLE` picks it for
+/// the deployment and `@style` for a single group or room.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AnswerStyle {
+    /// A plain text "yes" or "no".
+    #[default]
+    Text,
+    /// A Flex Message bubble, see [`oracle_bubble`].
+    Flex,
+}
+
+impl AnswerStyle {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "text" => Some(AnswerStyle::Text),
+            "flex" => Some(AnswerStyle::Flex),
+            _ => None,
+        }
+    }
+
+    pub fn from_env(env: &impl Secrets) -> Self {
+        env.get("DOLPHIN_ANSWER_STYLE")
+            .and_then(|var| Self::parse(&var))
+            .unwrap_or_default()
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AnswerStyle::Text => "text",
+            AnswerStyle::Flex => "flex",
+        }
+    }
+}
+
+/// The oracle's answer as a Flex bubble: the question, a big YES or NO and
+/// who asked, under an optional image. The alt text carries the same answer
+/// for notifications and clients without Flex support.
+pub fn oracle_bubble(
+    question: &str,
+    answer: &str,
+    asker: Option<&str>,
+    image_url: Option<&str>,
+) -> ReplyMessage {
+    let yes = answer.eq_ignore_ascii_case("yes");
+    let mut contents = Vec::new();
+    if !question.trim().is_empty() {
+        contents.push(json!({
+            "type": "text",
+            "text": question,
+            "wrap": true,
+            "size": "sm",
+            "color": "#666666",
+        }));
+    }
+    contents.push(json!({
+        "type": "text",
+        "text": answer.to_uppercase(),
+        "weight": "bold",
+        "size": "5xl",
+        "align": "center",
+        "color": if yes { "#1DB446" } else { "#E0457B" },
+    }));
+    if let Some(asker) = asker.filter(|asker| !asker.trim().is_empty()) {
+        contents.push(json!({
+            "type": "text",
+            "text": format!("asked by {}", asker),
+            "size": "xs",
+            "color": "#AAAAAA",
+            "align": "end",
+        }));
+    }
+
+    let mut bubble = json!({
+        "type": "bubble",
+        "body": {
+            "type": "box",
+            "layout": "vertical",
+            "spacing": "md",
+            "contents": contents,
+        },
+    });
+    if let Some(url) = image_url {
+        bubble["hero"] = json!({
+            "type": "image",
+            "url": url,
+            "size": "full",
+            "aspectRatio": "20:13",
+            "aspectMode": "cover",
+        });
+    }
+
+    let alt_text = match question.trim() {
+        "" => format!("🐬 {}", answer),
+        question => format!("🐬 {} → {}", question, answer),
+    };
+    ReplyMessage::flex(&alt_text, bubble)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    /// The texts of a bubble's body, in order.
+    fn body_texts(bubble: &Value) -> Vec<&str> {
+        bubble["body"]["contents"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|item| item["text"].as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_bubble_shows_question_answer_and_asker() {
+        let message = oracle_bubble(
+            "will it rain?",
+            "yes",
+            Some("Nemo"),
+            Some("https://x/d.png"),
+        );
+
+        let ReplyMessage::Flex {
+            alt_text, contents, ..
+        } = message
+        else {
+            panic!("expected a flex message, got {:?}", message);
+        };
+        assert_eq!(alt_text, "🐬 will it rain? → yes");
+        assert_eq!(
+            body_texts(&contents),
+            ["will it rain?", "YES", "asked by Nemo"]
+        );
+        assert_eq!(contents["hero"]["url"], "https://x/d.png");
+    }
+
+    #[test]
+    fn test_bubble_leaves_out_missing_parts() {
+        let ReplyMessage::Flex {
+            alt_text, contents, ..
+        } = oracle_bubble(" ", "no", None, None)
+        else {
+            panic!("expected a flex message");
+        };
+        assert_eq!(alt_text, "🐬 no");
+        assert_eq!(body_texts(&contents), ["NO"]);
+        assert!(contents.get("hero").is_none());
+    }
+
+    #[test]
+    fn test_parse_answer_style() {
+        assert_eq!(AnswerStyle::parse(" Flex"), Some(AnswerStyle::Flex));
+        assert_eq!(AnswerStyle::parse("TEXT"), Some(AnswerStyle::Text));
+        assert_eq!(AnswerStyle::parse("fancy"), None);
+    }
+}
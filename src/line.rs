@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::types::{Conversation, Profile, ReplyMessage};
+use crate::Result;
+
+/// Base URL of the real Messaging API; override with `LINE_API_BASE_URL`.
+pub const DEFAULT_API_BASE_URL: &str = "https://api.line.me";
+
+/// Outbound LINE Messaging API calls.
+#[allow(async_fn_in_trait)]
+pub trait LineClient {
+    async fn reply(&self, reply_token: &str, messages: Vec<ReplyMessage>) -> Result<()>;
+    async fn push(&self, to: &str, messages: Vec<ReplyMessage>) -> Result<()>;
+    /// Takes a chat over from the primary channel (module channels only).
+    /// Control returns to the primary channel after `ttl` unless released.
+    async fn acquire_chat_control(&self, chat_id: &str, ttl: Duration) -> Result<()>;
+    /// Hands a chat back to the primary channel.
+    async fn release_chat_control(&self, chat_id: &str) -> Result<()>;
+    /// Looks up a user, as a member of `chat` when it is a group or room.
+    async fn get_profile(&self, user_id: &str, chat: Conversation<'_>) -> Result<Profile>;
+}
+
+/// Messaging API path of a user's profile, seen from `chat`.
+pub fn profile_path(user_id: &str, chat: Conversation<'_>) -> String {
+    match chat {
+        Conversation::Group(group_id) => format!("/v2/bot/group/{}/member/{}", group_id, user_id),
+        Conversation::Room(room_id) => format!("/v2/bot/room/{}/member/{}", room_id, user_id),
+        Conversation::User(_) => format!("/v2/bot/profile/{}", user_id),
+    }
+}
+
+/// A call made through a [`RecordingLineClient`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum LineCall {
+    Reply {
+        reply_token: String,
+        messages: Vec<ReplyMessage>,
+    },
+    Push {
+        to: String,
+        messages: Vec<ReplyMessage>,
+    },
+    AcquireChatControl {
+        chat_id: String,
+    },
+    ReleaseChatControl {
+        chat_id: String,
+    },
+}
+
+/// Mock client that records every call in order instead of calling the API.
+/// Profile lookups are answered from [`RecordingLineClient::add_profile`]
+/// and not recorded.
+#[derive(Default)]
+pub struct RecordingLineClient {
+    calls: Mutex<Vec<LineCall>>,
+    reject_replies: AtomicBool,
+    display_names: Mutex<HashMap<String, String>>,
+}
+
+impl RecordingLineClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns all recorded calls, oldest first.
+    pub fn calls(&self) -> Vec<LineCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Returns and clears the recorded calls.
+    pub fn take_calls(&self) -> Vec<LineCall> {
+        std::mem::take(&mut *self.calls.lock().unwrap())
+    }
+
+    /// Makes replies fail like an expired reply token would. Rejected replies
+    /// are not recorded.
+    pub fn reject_replies(&self, reject: bool) {
+        self.reject_replies.store(reject, Ordering::Relaxed);
+    }
+
+    /// Makes profile lookups of `user_id` find `display_name`.
+    pub fn add_profile(&self, user_id: &str, display_name: &str) {
+        self.display_names
+            .lock()
+            .unwrap()
+            .insert(user_id.to_string(), display_name.to_string());
+    }
+}
+
+impl LineClient for RecordingLineClient {
+    async fn reply(&self, reply_token: &str, messages: Vec<ReplyMessage>) -> Result<()> {
+        if self.reject_replies.load(Ordering::Relaxed) {
+            return Err("Invalid reply token".into());
+        }
+        self.calls.lock().unwrap().push(LineCall::Reply {
+            reply_token: reply_token.to_string(),
+            messages,
+        });
+        Ok(())
+    }
+
+    async fn push(&self, to: &str, messages: Vec<ReplyMessage>) -> Result<()> {
+        self.calls.lock().unwrap().push(LineCall::Push {
+            to: to.to_string(),
+            messages,
+        });
+        Ok(())
+    }
+
+    async fn acquire_chat_control(&self, chat_id: &str, _ttl: Duration) -> Result<()> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(LineCall::AcquireChatControl {
+                chat_id: chat_id.to_string(),
+            });
+        Ok(())
+    }
+
+    async fn release_chat_control(&self, chat_id: &str) -> Result<()> {
+        self.calls
+            .lock()
+            .unwrap()
+            .push(LineCall::ReleaseChatControl {
+                chat_id: chat_id.to_string(),
+            });
+        Ok(())
+    }
+
+    async fn get_profile(&self, user_id: &str, _chat: Conversation<'_>) -> Result<Profile> {
+        let display_names = self.display_names.lock().unwrap();
+        let display_name = display_names.get(user_id).ok_or("Not found")?;
+        Ok(Profile {
+            display_name: display_name.clone(),
+            user_id: user_id.to_string(),
+            picture_url: None,
+        })
+    }
+}
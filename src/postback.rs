@@ -0,0 +1,179 @@
+use std::collections::BTreeMap;
+
+/// A `postback.data` string in query string form, such as
+/// `action=vote&poll=123&opt=2`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PostbackData {
+    pub action: String,
+    pub fields: BTreeMap<String, String>,
+}
+
+impl PostbackData {
+    pub fn new(action: &str) -> Self {
+        PostbackData {
+            action: action.to_string(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    /// Adds a field, builder style.
+    pub fn with(mut self, key: &str, value: &str) -> Self {
+        self.fields.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Parses postback data. Returns `None` when there is no `action`.
+    pub fn parse(data: &str) -> Option<Self> {
+        let mut action = None;
+        let mut fields = BTreeMap::new();
+        for pair in data.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let (key, value) = (decode(key), decode(value));
+            if key == "action" {
+                action = Some(value);
+            } else {
+                fields.insert(key, value);
+            }
+        }
+        Some(PostbackData {
+            action: action.filter(|action| !action.is_empty())?,
+            fields,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.fields.get(key).map(String::as_str)
+    }
+
+    /// Encodes the data for a postback action, `action` first.
+    pub fn encode(&self) -> String {
+        let mut pairs = vec![format!("action={}", encode(&self.action))];
+        for (key, value) in &self.fields {
+            pairs.push(format!("{}={}", encode(key), encode(value)));
+        }
+        pairs.join("&")
+    }
+}
+
+/// What the bot knows how to do with a postback.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PostbackAction {
+    /// Ask the dolphin a question: `action=ask&q=<question>`.
+    Ask { question: String },
+    /// Ask the dolphin to explain the answer it gave:
+    /// `action=why&q=<question>&a=<answer>`. The answer travels with the
+    /// button since anyone in a group can tap it, not just the asker.
+    Why { question: String, answer: String },
+    /// Flip a coin: `action=flip`.
+    Flip,
+}
+
+impl PostbackAction {
+    /// Maps postback data to an action. Returns `None` for unknown actions
+    /// and for known actions with missing fields.
+    pub fn from_data(data: &PostbackData) -> Option<Self> {
+        match data.action.as_str() {
+            "ask" => Some(PostbackAction::Ask {
+                question: data.get("q")?.to_string(),
+            }),
+            "why" => Some(PostbackAction::Why {
+                question: data.get("q")?.to_string(),
+                answer: data.get("a")?.to_string(),
+            }),
+            "flip" => Some(PostbackAction::Flip),
+            _ => None,
+        }
+    }
+
+    pub fn to_data(&self) -> PostbackData {
+        match self {
+            PostbackAction::Ask { question } => PostbackData::new("ask").with("q", question),
+            PostbackAction::Why { question, answer } => PostbackData::new("why")
+                .with("q", question)
+                .with("a", answer),
+            PostbackAction::Flip => PostbackData::new("flip"),
+        }
+    }
+}
+
+/// Percent-decodes a query string component, treating `+` as a space.
+fn decode(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = component.get(i + 1..i + 3);
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Percent-encodes anything but `[A-Za-z0-9_.~-]`.
+fn encode(component: &str) -> String {
+    let mut encoded = String::with_capacity(component.len());
+    for byte in component.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'_' | b'.' | b'~' | b'-') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_query_string_data() {
+        let data = PostbackData::parse("action=vote&poll=123&opt=2&note=a+b%26c").unwrap();
+
+        assert_eq!(data.action, "vote");
+        assert_eq!(data.get("poll"), Some("123"));
+        assert_eq!(data.get("opt"), Some("2"));
+        assert_eq!(data.get("note"), Some("a b&c"));
+        assert_eq!(PostbackData::parse("poll=123"), None);
+        assert_eq!(PostbackData::parse("action="), None);
+    }
+
+    #[test]
+    fn test_actions_round_trip() {
+        let action = PostbackAction::Ask {
+            question: "will it rain? 🐬".to_string(),
+        };
+        let encoded = action.to_data().encode();
+
+        assert!(encoded.starts_with("action=ask&q="));
+        let data = PostbackData::parse(&encoded).unwrap();
+        assert_eq!(PostbackAction::from_data(&data), Some(action));
+        assert_eq!(PostbackAction::from_data(&PostbackData::new("ask")), None);
+        let why = PostbackAction::Why {
+            question: "rain?".to_string(),
+            answer: "yes".to_string(),
+        };
+        assert_eq!(why.to_data().encode(), "action=why&a=yes&q=rain%3F");
+        assert_eq!(
+            PostbackAction::from_data(&PostbackData::parse("action=why&q=rain%3F").unwrap()),
+            None
+        );
+        assert_eq!(
+            PostbackAction::from_data(&PostbackData::parse("action=flip").unwrap()),
+            Some(PostbackAction::Flip)
+        );
+        assert_eq!(PostbackAction::from_data(&PostbackData::new("vote")), None);
+    }
+}
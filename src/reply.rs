@@ -0,0 +1,185 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::types::ReplyMessage;
+
+/// The most messages LINE accepts in one reply or push request.
+pub const MAX_MESSAGES: usize = 5;
+
+/// The longest text message LINE accepts, counted in UTF-16 code units like
+/// LINE does.
+pub const MAX_TEXT_LENGTH: usize = 5000;
+
+/// Collects the messages of one answer and fits them into LINE's limits.
+///
+/// Text messages over [`MAX_TEXT_LENGTH`] are split into several, preferably
+/// at line breaks and never inside a grapheme. [`ReplyBuilder::build`] then
+/// batches the messages: the first batch is the reply, any further ones are
+/// follow-up pushes.
+#[derive(Debug, Default)]
+pub struct ReplyBuilder {
+    messages: Vec<ReplyMessage>,
+}
+
+impl ReplyBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn text(self, text: &str) -> Self {
+        self.message(ReplyMessage::text(text))
+    }
+
+    pub fn message(mut self, message: ReplyMessage) -> Self {
+        match message {
+            ReplyMessage::Text {
+                text,
+                quote_token,
+                quick_reply,
+            } if utf16_len(&text) > MAX_TEXT_LENGTH => {
+                // The first part quotes and the last part keeps the buttons,
+                // which LINE only shows under the last message
+                let parts = split_text(&text, MAX_TEXT_LENGTH);
+                let last = parts.len() - 1;
+                for (index, part) in parts.into_iter().enumerate() {
+                    self.messages.push(ReplyMessage::Text {
+                        text: part,
+                        quote_token: quote_token.clone().filter(|_| index == 0),
+                        quick_reply: quick_reply.clone().filter(|_| index == last),
+                    });
+                }
+            }
+            message => self.messages.push(message),
+        }
+        self
+    }
+
+    pub fn messages(self, messages: impl IntoIterator<Item = ReplyMessage>) -> Self {
+        messages.into_iter().fold(self, Self::message)
+    }
+
+    /// The messages in batches of at most [`MAX_MESSAGES`], in order.
+    pub fn build(self) -> Vec<Vec<ReplyMessage>> {
+        self.messages
+            .chunks(MAX_MESSAGES)
+            .map(<[ReplyMessage]>::to_vec)
+            .collect()
+    }
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+/// Splits text into parts of at most `max_len` UTF-16 code units. Parts end
+/// at a line break where possible, and lines too long for a part of their
+/// own are split between graphemes.
+fn split_text(text: &str, max_len: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut part_len = 0;
+    let mut flush = |part: &mut String, part_len: &mut usize| {
+        let taken = std::mem::take(part);
+        let trimmed = taken.strip_suffix('\n').unwrap_or(&taken);
+        if !trimmed.is_empty() {
+            parts.push(trimmed.to_string());
+        }
+        *part_len = 0;
+    };
+
+    for line in text.split_inclusive('\n') {
+        let line_len = utf16_len(line);
+        if part_len + line_len > max_len {
+            flush(&mut part, &mut part_len);
+        }
+        if line_len <= max_len {
+            part.push_str(line);
+            part_len += line_len;
+            continue;
+        }
+        for grapheme in line.graphemes(true) {
+            let grapheme_len = utf16_len(grapheme);
+            if part_len + grapheme_len > max_len {
+                flush(&mut part, &mut part_len);
+            }
+            part.push_str(grapheme);
+            part_len += grapheme_len;
+        }
+    }
+    flush(&mut part, &mut part_len);
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Action, QuickReply};
+
+    fn texts(batch: &[ReplyMessage]) -> Vec<&str> {
+        batch
+            .iter()
+            .map(|message| match message {
+                ReplyMessage::Text { text, .. } => text.as_str(),
+                _ => panic!("not a text message: {:?}", message),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_split_text_prefers_line_breaks() {
+        assert_eq!(split_text("short", 10), vec!["short"]);
+        assert_eq!(
+            split_text("one\ntwo\nthree\n", 10),
+            vec!["one\ntwo", "three"]
+        );
+        assert_eq!(
+            split_text("abcdefghijkl\nxy", 5),
+            vec!["abcde", "fghij", "kl\nxy"]
+        );
+    }
+
+    #[test]
+    fn test_split_text_keeps_graphemes_whole() {
+        // 👍🏽 is four UTF-16 code units, 🇹🇭 is four too
+        assert_eq!(split_text("👍🏽👍🏽🇹🇭", 6), vec!["👍🏽", "👍🏽", "🇹🇭"]);
+        assert_eq!(
+            split_text("e\u{301}e\u{301}", 3),
+            vec!["e\u{301}", "e\u{301}"]
+        );
+    }
+
+    #[test]
+    fn test_build_splits_and_batches() {
+        let long = format!("{}\n{}", "a".repeat(4000), "b".repeat(4000));
+        let buttons = QuickReply::new(vec![Action::message("Ask again", "@dolphin ?")]);
+        let batches = ReplyBuilder::new()
+            .message(ReplyMessage::quoted_text(&long, "q1").with_quick_reply(buttons.clone()))
+            .messages((1..=4).map(|n| ReplyMessage::text(&n.to_string())))
+            .build();
+
+        assert_eq!(batches.len(), 2);
+        assert_eq!(
+            texts(&batches[0]),
+            vec![&"a".repeat(4000), &"b".repeat(4000), "1", "2", "3"]
+        );
+        assert_eq!(texts(&batches[1]), vec!["4"]);
+        assert!(matches!(
+            &batches[0][0],
+            ReplyMessage::Text {
+                quote_token: Some(_),
+                quick_reply: None,
+                ..
+            }
+        ));
+        assert!(matches!(
+            &batches[0][1],
+            ReplyMessage::Text { quote_token: None, quick_reply: Some(q), .. } if *q == buttons
+        ));
+    }
+
+    #[test]
+    fn test_build_leaves_short_messages_alone() {
+        let batches = ReplyBuilder::new().text("yes").build();
+        assert_eq!(batches, vec![vec![ReplyMessage::text("yes")]]);
+        assert!(ReplyBuilder::new().build().is_empty());
+    }
+}
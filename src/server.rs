@@ -0,0 +1,553 @@
+use axum::{
+    body::Bytes,
+    extract::{Json, Path, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Router,
+};
+use log::{error, info};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::path::Path as FilePath;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::channel::{Channel, Channels};
+use crate::dolphin::{Dolphin, Secrets, WebhookError};
+use crate::line::{profile_path, LineClient, DEFAULT_API_BASE_URL};
+use crate::state::{FileStore, MemoryStore, SqliteStore, StateStore};
+use crate::types::{
+    AcquireControlRequest, Conversation, Profile, PushRequest, ReplyMessage, ReplyRequest,
+    WebhookEvent, WebhookRequest,
+};
+use crate::Result;
+
+/// Reads configuration from the process environment.
+pub struct ProcessEnv;
+
+impl Secrets for ProcessEnv {
+    fn get(&self, key: &str) -> Option<String> {
+        env::var(key).ok()
+    }
+}
+
+/// State store selected with `STATE_BACKEND` (`file`, `sqlite` or `memory`).
+pub enum ServerStore {
+    File(FileStore),
+    Sqlite(SqliteStore),
+    Memory(MemoryStore),
+}
+
+impl ServerStore {
+    pub fn from_env() -> Result<Self> {
+        let backend = env::var("STATE_BACKEND").unwrap_or_else(|_| "file".to_string());
+        match backend.as_str() {
+            "file" => {
+                let dir = env::var("STATE_DIR").unwrap_or_else(|_| "state".to_string());
+                Ok(ServerStore::File(FileStore::new(dir)?))
+            }
+            "sqlite" => {
+                let path = env::var("STATE_DB").unwrap_or_else(|_| "dolphin.db".to_string());
+                Ok(ServerStore::Sqlite(SqliteStore::open(&path)?))
+            }
+            "memory" => Ok(ServerStore::Memory(MemoryStore::new())),
+            other => Err(format!("Unknown STATE_BACKEND: {}", other).into()),
+        }
+    }
+
+    /// Carries the @on/@off switch over from the `REPLY_STATE_FILE` (default
+    /// `reply_state.txt`) used before the state store, unless the store
+    /// already has one.
+    pub async fn migrate_reply_state_file(&self) -> Result<()> {
+        let path = env::var("REPLY_STATE_FILE").unwrap_or_else(|_| "reply_state.txt".to_string());
+        import_reply_state_file(self, FilePath::new(&path)).await
+    }
+}
+
+async fn import_reply_state_file(store: &impl StateStore, path: &FilePath) -> Result<()> {
+    let state = match fs::read_to_string(path) {
+        Ok(state) => state,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if store.get("enabled").await?.is_none() {
+        info!("Importing reply state from {}", path.display());
+        store.put("enabled", state.trim()).await?;
+    }
+    Ok(())
+}
+
+impl StateStore for ServerStore {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        match self {
+            ServerStore::File(store) => store.get(key).await,
+            ServerStore::Sqlite(store) => store.get(key).await,
+            ServerStore::Memory(store) => store.get(key).await,
+        }
+    }
+
+    async fn put(&self, key: &str, value: &str) -> Result<()> {
+        match self {
+            ServerStore::File(store) => store.put(key, value).await,
+            ServerStore::Sqlite(store) => store.put(key, value).await,
+            ServerStore::Memory(store) => store.put(key, value).await,
+        }
+    }
+
+    async fn put_with_ttl(&self, key: &str, value: &str, ttl: Duration) -> Result<()> {
+        match self {
+            ServerStore::File(store) => store.put_with_ttl(key, value, ttl).await,
+            ServerStore::Sqlite(store) => store.put_with_ttl(key, value, ttl).await,
+            ServerStore::Memory(store) => store.put_with_ttl(key, value, ttl).await,
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match self {
+            ServerStore::File(store) => store.delete(key).await,
+            ServerStore::Sqlite(store) => store.delete(key).await,
+            ServerStore::Memory(store) => store.delete(key).await,
+        }
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        match self {
+            ServerStore::File(store) => store.keys(prefix).await,
+            ServerStore::Sqlite(store) => store.keys(prefix).await,
+            ServerStore::Memory(store) => store.keys(prefix).await,
+        }
+    }
+}
+
+/// LINE Messaging API client using reqwest.
+pub struct ReqwestClient {
+    client: reqwest::Client,
+    api_base_url: String,
+    channel_access_token: String,
+}
+
+impl ReqwestClient {
+    pub fn from_env(env: &impl Secrets) -> Result<Self> {
+        Ok(ReqwestClient {
+            client: reqwest::Client::new(),
+            api_base_url: env
+                .get("LINE_API_BASE_URL")
+                .unwrap_or_else(|| DEFAULT_API_BASE_URL.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            channel_access_token: env
+                .get("LINE_CHANNEL_ACCESS_TOKEN")
+                .ok_or("LINE_CHANNEL_ACCESS_TOKEN must be set")?,
+        })
+    }
+
+    async fn post<T: Serialize>(&self, path: &str, payload: &T) -> Result<()> {
+        let response = self
+            .client
+            .post(format!("{}{}", self.api_base_url, path))
+            .header(
+                "Authorization",
+                format!("Bearer {}", self.channel_access_token),
+            )
+            .header("Content-Type", "application/json")
+            .json(payload)
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            error!("LINE API error: {}", error_text);
+
+            // Check for specific error types
+            if error_text.contains("Invalid reply token") {
+                error!("Reply token is invalid or expired. This can happen if:");
+                error!("1. The token has expired (tokens are valid for ~30 seconds)");
+                error!("2. The token has already been used");
+                error!("3. The token is malformed");
+            }
+
+            return Err(format!("LINE API error: {}", error_text).into());
+        }
+
+        Ok(())
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let response = self
+            .client
+            .get(format!("{}{}", self.api_base_url, path))
+            .header(
+                "Authorization",
+                format!("Bearer {}", self.channel_access_token),
+            )
+            .send()
+            .await?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await?;
+            return Err(format!("LINE API error: {}", error_text).into());
+        }
+
+        Ok(response.json().await?)
+    }
+}
+
+impl LineClient for ReqwestClient {
+    async fn reply(&self, reply_token: &str, messages: Vec<ReplyMessage>) -> Result<()> {
+        let reply_request = ReplyRequest {
+            reply_token: reply_token.to_string(),
+            messages,
+        };
+        self.post("/v2/bot/message/reply", &reply_request).await
+    }
+
+    async fn push(&self, to: &str, messages: Vec<ReplyMessage>) -> Result<()> {
+        let push_request = PushRequest {
+            to: to.to_string(),
+            messages,
+        };
+        self.post("/v2/bot/message/push", &push_request).await
+    }
+
+    async fn acquire_chat_control(&self, chat_id: &str, ttl: Duration) -> Result<()> {
+        let acquire_request = AcquireControlRequest {
+            expired: true,
+            ttl: ttl.as_secs(),
+        };
+        let path = format!("/v2/bot/chat/{}/control/acquire", chat_id);
+        self.post(&path, &acquire_request).await
+    }
+
+    async fn release_chat_control(&self, chat_id: &str) -> Result<()> {
+        let path = format!("/v2/bot/chat/{}/control/release", chat_id);
+        self.post(&path, &serde_json::json!({})).await
+    }
+
+    async fn get_profile(&self, user_id: &str, chat: Conversation<'_>) -> Result<Profile> {
+        self.get(&profile_path(user_id, chat)).await
+    }
+}
+
+/// Number of tasks handling webhook events when `WEBHOOK_WORKERS` is unset.
+const DEFAULT_WEBHOOK_WORKERS: usize = 4;
+
+/// A pool of tokio tasks that handles webhook events after the request has
+/// been answered.
+///
+/// Events are spread over the tasks by channel and conversation, so events
+/// from the same chat are always handled by the same task, one at a time and
+/// in order.
+///
+/// Dropping the queue stops the tasks once they have handled every event
+/// already queued; [`EventWorkers::join`] waits for that.
+pub struct EventQueue {
+    workers: Vec<mpsc::UnboundedSender<(Channel, WebhookEvent)>>,
+}
+
+/// The tasks behind an [`EventQueue`].
+pub struct EventWorkers {
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl EventWorkers {
+    /// Waits for the tasks to finish, which they do after the queue has been
+    /// dropped and emptied.
+    pub async fn join(self) {
+        for handle in self.handles {
+            if let Err(e) = handle.await {
+                error!("Event worker failed: {}", e);
+            }
+        }
+    }
+}
+
+impl EventQueue {
+    /// Starts a pool sized by `WEBHOOK_WORKERS`.
+    pub fn from_env<F, Fut>(handler: F) -> (Self, EventWorkers)
+    where
+        F: Fn(Channel, WebhookEvent) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let size = env::var("WEBHOOK_WORKERS")
+            .ok()
+            .and_then(|var| var.parse().ok())
+            .unwrap_or(DEFAULT_WEBHOOK_WORKERS);
+        Self::start(size, handler)
+    }
+
+    /// Starts `size` tasks (at least one) that pass events to `handler`.
+    pub fn start<F, Fut>(size: usize, handler: F) -> (Self, EventWorkers)
+    where
+        F: Fn(Channel, WebhookEvent) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let (workers, handles) = (0..size.max(1))
+            .map(|_| {
+                let (sender, mut receiver) = mpsc::unbounded_channel();
+                let handler = handler.clone();
+                let handle = tokio::spawn(async move {
+                    while let Some((channel, event)) = receiver.recv().await {
+                        handler(channel, event).await;
+                    }
+                });
+                (sender, handle)
+            })
+            .unzip();
+        (EventQueue { workers }, EventWorkers { handles })
+    }
+
+    /// Queues a channel's events for handling, keeping their order within
+    /// each chat.
+    pub fn enqueue(&self, channel: &Channel, events: Vec<WebhookEvent>) {
+        for event in events {
+            let mut hasher = DefaultHasher::new();
+            channel.name.hash(&mut hasher);
+            event.source.chat_id().hash(&mut hasher);
+            let worker = (hasher.finish() % self.workers.len() as u64) as usize;
+            if self.workers[worker].send((channel.clone(), event)).is_err() {
+                error!("Event worker {} has stopped, dropping event", worker);
+            }
+        }
+    }
+}
+
+/// Runtime adapters shared by all webhook requests and event workers.
+pub struct AppState<E, S, L> {
+    pub env: E,
+    pub store: S,
+    pub channels: Channels,
+    /// LINE clients by channel name, the default channel being `""`.
+    pub lines: HashMap<String, L>,
+}
+
+impl<E: Secrets, S: StateStore, L: LineClient> AppState<E, S, L> {
+    /// Handles one queued event as the channel it arrived on.
+    pub async fn handle_event(&self, channel: &Channel, event: &WebhookEvent) {
+        let Some(line) = self.lines.get(&channel.name) else {
+            return;
+        };
+        let (env, store) = (channel.env(&self.env), channel.store(&self.store));
+        let dolphin = Dolphin::new(&env, &store, line);
+        dolphin.handle_event(event).await;
+    }
+}
+
+/// What the webhook handlers need: the adapters, to verify and parse
+/// requests, and the queue that handles their events.
+pub struct AppContext<E, S, L> {
+    pub state: Arc<AppState<E, S, L>>,
+    pub queue: EventQueue,
+}
+
+/// The server's routes: health checks, `/debug`, and the LINE webhooks at
+/// `/webhook` and `/webhook/{channel}`.
+pub fn router<E, S, L>(app: AppContext<E, S, L>) -> Router
+where
+    E: Secrets + Send + Sync + 'static,
+    S: StateStore + Send + Sync + 'static,
+    L: LineClient + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/", get(health_check))
+        .route("/debug", get(debug_handler))
+        .route("/debug", post(debug_handler))
+        .route("/webhook", get(health_check))
+        .route("/webhook", post(webhook_handler::<E, S, L>))
+        .route(
+            "/webhook/{channel}",
+            post(channel_webhook_handler::<E, S, L>),
+        )
+        .with_state(Arc::new(app))
+}
+
+#[derive(Debug, Serialize)]
+struct HealthResponse {
+    status: String,
+    version: String,
+}
+
+async fn health_check() -> impl IntoResponse {
+    let health_response = HealthResponse {
+        status: "ok".to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+
+    (StatusCode::OK, Json(health_response))
+}
+
+async fn debug_handler(_headers: HeaderMap, raw_body: Bytes) -> impl IntoResponse {
+    // Read the raw body for debugging
+    let body_bytes = raw_body;
+
+    // Log the raw body for debugging
+    if let Ok(body_str) = String::from_utf8(body_bytes.clone().to_vec()) {
+        info!("Raw request body: {}", body_str);
+    }
+
+    // Try to deserialize the JSON
+    let _webhook_request: WebhookRequest = match serde_json::from_slice(&body_bytes) {
+        Ok(req) => req,
+        Err(e) => {
+            error!("Failed to deserialize JSON: {}", e);
+            return StatusCode::UNPROCESSABLE_ENTITY;
+        }
+    };
+
+    StatusCode::OK
+}
+
+async fn webhook_handler<E: Secrets, S: StateStore, L: LineClient>(
+    State(app): State<Arc<AppContext<E, S, L>>>,
+    headers: HeaderMap,
+    raw_body: Bytes,
+) -> impl IntoResponse {
+    handle_webhook(&app, None, &headers, &raw_body)
+}
+
+async fn channel_webhook_handler<E: Secrets, S: StateStore, L: LineClient>(
+    State(app): State<Arc<AppContext<E, S, L>>>,
+    Path(channel): Path<String>,
+    headers: HeaderMap,
+    raw_body: Bytes,
+) -> impl IntoResponse {
+    handle_webhook(&app, Some(&channel), &headers, &raw_body)
+}
+
+fn handle_webhook<E: Secrets, S: StateStore, L: LineClient>(
+    app: &AppContext<E, S, L>,
+    path_channel: Option<&str>,
+    headers: &HeaderMap,
+    raw_body: &[u8],
+) -> StatusCode {
+    let signature = headers
+        .get("x-line-signature")
+        .and_then(|h| h.to_str().ok());
+
+    let state = &app.state;
+    let channel = match state.channels.route(path_channel, raw_body) {
+        Ok(channel) => channel,
+        Err(e) => return error_status(e),
+    };
+    let Some(line) = state.lines.get(&channel.name) else {
+        error!("No LINE channel configured for this webhook");
+        return StatusCode::NOT_FOUND;
+    };
+
+    let (env, store) = (channel.env(&state.env), channel.store(&state.store));
+    let dolphin = Dolphin::new(&env, &store, line);
+
+    // Answer LINE right away and leave the events to the worker pool
+    match dolphin.parse_webhook(raw_body, signature) {
+        Ok(webhook_request) => {
+            app.queue.enqueue(&channel, webhook_request.events);
+            StatusCode::OK
+        }
+        Err(e) => error_status(e),
+    }
+}
+
+fn error_status(error: WebhookError) -> StatusCode {
+    match error {
+        WebhookError::Unauthorized => StatusCode::UNAUTHORIZED,
+        WebhookError::BadRequest => StatusCode::BAD_REQUEST,
+        WebhookError::UnknownChannel => StatusCode::NOT_FOUND,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Mutex;
+
+    fn text_event(user_id: &str, text: &str) -> WebhookEvent {
+        serde_json::from_value(json!({
+            "type": "message",
+            "timestamp": 1700000000000u64,
+            "source": { "type": "user", "userId": user_id },
+            "webhookEventId": format!("{}-{}", user_id, text),
+            "deliveryContext": { "isRedelivery": false },
+            "mode": "active",
+            "replyToken": "reply",
+            "message": { "type": "text", "id": text, "text": text }
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_imports_reply_state_file_once() {
+        let path = env::temp_dir().join(format!("dolphin-reply-state-{}", std::process::id()));
+        let store = MemoryStore::new();
+
+        import_reply_state_file(&store, &path).await.unwrap();
+        assert_eq!(store.get("enabled").await.unwrap(), None);
+
+        fs::write(&path, "disabled\n").unwrap();
+        import_reply_state_file(&store, &path).await.unwrap();
+        assert_eq!(
+            store.get("enabled").await.unwrap().as_deref(),
+            Some("disabled")
+        );
+
+        // The store wins once it has a state of its own
+        store.put("enabled", "enabled").await.unwrap();
+        import_reply_state_file(&store, &path).await.unwrap();
+        assert_eq!(
+            store.get("enabled").await.unwrap().as_deref(),
+            Some("enabled")
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keeps_order_within_a_chat() {
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let (done, mut finished) = mpsc::unbounded_channel();
+        let (queue, workers) = EventQueue::start(3, {
+            let handled = handled.clone();
+            move |_: Channel, event: WebhookEvent| {
+                let handled = handled.clone();
+                let done = done.clone();
+                async move {
+                    // Make early events slower so a reordering would show
+                    let delay = 20 - event.webhook_event_id.len() as u64 % 20;
+                    tokio::time::sleep(Duration::from_millis(delay)).await;
+                    handled.lock().unwrap().push(event.webhook_event_id);
+                    done.send(()).unwrap();
+                }
+            }
+        });
+
+        let mut events = Vec::new();
+        for i in 0..10 {
+            for user in ["Ua", "Ub", "Uc"] {
+                events.push(text_event(user, &"x".repeat(i + 1)));
+            }
+        }
+        queue.enqueue(&Channel::default(), events);
+        drop(queue);
+        workers.join().await;
+        for _ in 0..30 {
+            finished.try_recv().unwrap();
+        }
+
+        let handled = handled.lock().unwrap();
+        for user in ["Ua", "Ub", "Uc"] {
+            let texts: Vec<usize> = handled
+                .iter()
+                .filter_map(|id| id.strip_prefix(&format!("{}-", user)))
+                .map(str::len)
+                .collect();
+            assert_eq!(texts, (1..=10).collect::<Vec<_>>());
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::Result;
+
+/// Persistent key/value storage for all bot state (reply switch, message
+/// history, ...). Values are strings; use the JSON helpers for structured data.
+#[allow(async_fn_in_trait)]
+pub trait StateStore {
+    async fn get(&self, key: &str) -> Result<Option<String>>;
+    async fn put(&self, key: &str, value: &str) -> Result<()>;
+    /// Stores a value that expires after `ttl`.
+    async fn put_with_ttl(&self, key: &str, value: &str, ttl: Duration) -> Result<()>;
+    async fn delete(&self, key: &str) -> Result<()>;
+    /// Lists the unexpired keys that start with `prefix`, in order.
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>>;
+
+    async fn get_json<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        match self.get(key).await? {
+            Some(content) => Ok(Some(serde_json::from_str(&content)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put_json<T: Serialize>(&self, key: &str, value: &T) -> Result<()> {
+        self.put(key, &serde_json::to_string(value)?).await
+    }
+
+    async fn put_json_with_ttl<T: Serialize>(
+        &self,
+        key: &str,
+        value: &T,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.put_with_ttl(key, &serde_json::to_string(value)?, ttl)
+            .await
+    }
+}
+
+/// A stored value with its optional expiry (seconds since the Unix epoch).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    value: String,
+    expires_at: Option<u64>,
+}
+
+impl Entry {
+    fn new(value: &str, ttl: Option<Duration>) -> Self {
+        Entry {
+            value: value.to_string(),
+            expires_at: ttl.map(|ttl| now_secs() + ttl.as_secs()),
+        }
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|at| at <= now_secs())
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Process-local store, used by tests and local tools.
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl StateStore for MemoryStore {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.is_expired() => {
+                entries.remove(key);
+                Ok(None)
+            }
+            Some(entry) => Ok(Some(entry.value.clone())),
+            None => Ok(None),
+        }
+    }
+
+    async fn put(&self, key: &str, value: &str) -> Result<()> {
+        let entry = Entry::new(value, None);
+        self.entries.lock().unwrap().insert(key.to_string(), entry);
+        Ok(())
+    }
+
+    async fn put_with_ttl(&self, key: &str, value: &str, ttl: Duration) -> Result<()> {
+        let entry = Entry::new(value, Some(ttl));
+        self.entries.lock().unwrap().insert(key.to_string(), entry);
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys: Vec<String> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, entry)| key.starts_with(prefix) && !entry.is_expired())
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+/// Stores each key as a JSON file in a flat directory.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(FileStore { dir })
+    }
+
+    /// Maps a key to a file name, percent-encoding anything but `[A-Za-z0-9_-]`.
+    fn path(&self, key: &str) -> PathBuf {
+        let mut name = String::with_capacity(key.len());
+        for byte in key.bytes() {
+            if byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' {
+                name.push(byte as char);
+            } else {
+                name.push_str(&format!("%{:02X}", byte));
+            }
+        }
+        self.dir.join(name)
+    }
+
+    /// Maps a file name back to its key; `None` for files [`FileStore::path`]
+    /// can't have made.
+    fn key(name: &str) -> Option<String> {
+        let mut bytes = Vec::with_capacity(name.len());
+        let mut rest = name.as_bytes();
+        while let Some((&byte, tail)) = rest.split_first() {
+            if byte == b'%' {
+                let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &tail[2..];
+            } else {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+        String::from_utf8(bytes).ok()
+    }
+
+    fn write(&self, key: &str, entry: &Entry) -> Result<()> {
+        fs::write(self.path(key), serde_json::to_string(entry)?)?;
+        Ok(())
+    }
+}
+
+impl StateStore for FileStore {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        let path = self.path(key);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let entry: Entry = serde_json::from_str(&content)?;
+        if entry.is_expired() {
+            let _ = fs::remove_file(&path);
+            return Ok(None);
+        }
+        Ok(Some(entry.value))
+    }
+
+    async fn put(&self, key: &str, value: &str) -> Result<()> {
+        self.write(key, &Entry::new(value, None))
+    }
+
+    async fn put_with_ttl(&self, key: &str, value: &str, ttl: Duration) -> Result<()> {
+        self.write(key, &Entry::new(value, Some(ttl)))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match fs::remove_file(self.path(key)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for file in fs::read_dir(&self.dir)? {
+            let name = file?.file_name();
+            let Some(key) = name.to_str().and_then(FileStore::key) else {
+                continue;
+            };
+            // Reading the value drops the file if it has expired
+            if key.starts_with(prefix) && self.get(&key).await?.is_some() {
+                keys.push(key);
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+/// SQLite-backed store for the native server.
+#[cfg(feature = "server")]
+pub struct SqliteStore {
+    conn: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "server")]
+impl SqliteStore {
+    pub fn open(path: &str) -> Result<Self> {
+        let conn = rusqlite::Connection::open(path)?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS state (
+                key TEXT PRIMARY KEY,
+                value TEXT NOT NULL,
+                expires_at INTEGER
+            )",
+            (),
+        )?;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn write(&self, key: &str, entry: &Entry) -> Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO state (key, value, expires_at) VALUES (?1, ?2, ?3)",
+            (key, &entry.value, entry.expires_at.map(|at| at as i64)),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "server")]
+impl StateStore for SqliteStore {
+    async fn get(&self, key: &str) -> Result<Option<String>> {
+        use rusqlite::OptionalExtension;
+
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM state WHERE key = ?1 AND expires_at <= ?2",
+            (key, now_secs() as i64),
+        )?;
+        let value = conn
+            .query_row("SELECT value FROM state WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(value)
+    }
+
+    async fn put(&self, key: &str, value: &str) -> Result<()> {
+        self.write(key, &Entry::new(value, None))
+    }
+
+    async fn put_with_ttl(&self, key: &str, value: &str, ttl: Duration) -> Result<()> {
+        self.write(key, &Entry::new(value, Some(ttl)))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM state WHERE key = ?1", [key])?;
+        Ok(())
+    }
+
+    async fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT key FROM state
+             WHERE substr(key, 1, length(?1)) = ?1
+               AND (expires_at IS NULL OR expires_at > ?2)
+             ORDER BY key",
+        )?;
+        let keys = statement
+            .query_map((prefix, now_secs() as i64), |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn exercise(store: &impl StateStore) {
+        assert_eq!(store.get("enabled").await.unwrap(), None);
+
+        store.put("enabled", "disabled").await.unwrap();
+        assert_eq!(
+            store.get("enabled").await.unwrap().as_deref(),
+            Some("disabled")
+        );
+
+        store.delete("enabled").await.unwrap();
+        assert_eq!(store.get("enabled").await.unwrap(), None);
+        store.delete("enabled").await.unwrap();
+
+        store
+            .put_with_ttl("expired", "gone", Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(store.get("expired").await.unwrap(), None);
+        store
+            .put_with_ttl("fresh", "here", Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(store.get("fresh").await.unwrap().as_deref(), Some("here"));
+
+        store
+            .put_json("msg_history:C1", &vec!["a", "b"])
+            .await
+            .unwrap();
+        let history: Option<Vec<String>> = store.get_json("msg_history:C1").await.unwrap();
+        assert_eq!(history, Some(vec!["a".to_string(), "b".to_string()]));
+
+        store.put("group:C2", "{}").await.unwrap();
+        store.put("group:C1", "{}").await.unwrap();
+        store.put("group_templates:C1", "{}").await.unwrap();
+        store
+            .put_with_ttl("group:C3", "{}", Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(
+            store.keys("group:").await.unwrap(),
+            vec!["group:C1", "group:C2"]
+        );
+        assert!(store.keys("none:").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_memory_store() {
+        exercise(&MemoryStore::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_file_store() {
+        let dir = std::env::temp_dir().join(format!("dolphin-state-{}", std::process::id()));
+        exercise(&FileStore::new(&dir).unwrap()).await;
+        assert!(dir.join("msg_history%3AC1").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "server")]
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        exercise(&SqliteStore::open(":memory:").unwrap()).await;
+    }
+}
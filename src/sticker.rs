@@ -0,0 +1,148 @@
+use serde::{Deserialize, Serialize};
+
+use crate::dolphin::Secrets;
+use crate::types::ReplyMessage;
+
+/// A LINE sticker, written `<packageId>/<stickerId>` in configuration.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StickerRef {
+    pub package_id: String,
+    pub sticker_id: String,
+}
+
+impl StickerRef {
+    fn parse(value: &str) -> Option<Self> {
+        let (package_id, sticker_id) = value.trim().split_once('/')?;
+        if package_id.is_empty() || sticker_id.is_empty() {
+            return None;
+        }
+        Some(StickerRef {
+            package_id: package_id.to_string(),
+            sticker_id: sticker_id.to_string(),
+        })
+    }
+
+    pub fn to_message(&self) -> ReplyMessage {
+        ReplyMessage::sticker(&self.package_id, &self.sticker_id)
+    }
+}
+
+/// What an incoming sticker has to look like for a rule to apply.
+#[derive(Debug, PartialEq)]
+enum StickerMatch {
+    /// `<packageId>/<stickerId>`
+    Sticker(StickerRef),
+    /// `<packageId>/*`
+    Package(String),
+    /// Anything else, compared case-insensitively with the sticker's keywords.
+    Keyword(String),
+}
+
+/// How the dolphin answers stickers.
+///
+/// `DOLPHIN_STICKER_REPLIES` holds comma-separated `<match>=<packageId>/<stickerId>`
+/// rules, where `<match>` is a sticker, a whole package (`<packageId>/*`) or a
+/// keyword. `DOLPHIN_STICKER_YES` and `DOLPHIN_STICKER_NO` turn on the sticker
+/// oracle, which answers any other sticker in a DM with one of the two.
+#[derive(Debug, Default)]
+pub struct StickerConfig {
+    rules: Vec<(StickerMatch, StickerRef)>,
+    oracle: Option<(StickerRef, StickerRef)>,
+}
+
+impl StickerConfig {
+    pub fn from_env(env: &impl Secrets) -> Self {
+        let rules = env
+            .get("DOLPHIN_STICKER_REPLIES")
+            .map(|var| var.split(',').filter_map(Self::parse_rule).collect())
+            .unwrap_or_default();
+
+        let sticker = |key: &str| env.get(key).and_then(|var| StickerRef::parse(&var));
+        let oracle = sticker("DOLPHIN_STICKER_YES").zip(sticker("DOLPHIN_STICKER_NO"));
+
+        StickerConfig { rules, oracle }
+    }
+
+    fn parse_rule(rule: &str) -> Option<(StickerMatch, StickerRef)> {
+        let (pattern, reply) = rule.rsplit_once('=')?;
+        let pattern = pattern.trim();
+        let matcher = match pattern.split_once('/') {
+            Some((package_id, "*")) if !package_id.is_empty() => {
+                StickerMatch::Package(package_id.to_string())
+            }
+            Some(_) => StickerMatch::Sticker(StickerRef::parse(pattern)?),
+            None if !pattern.is_empty() => StickerMatch::Keyword(pattern.to_lowercase()),
+            None => return None,
+        };
+        Some((matcher, StickerRef::parse(reply)?))
+    }
+
+    /// Finds the configured reply for a sticker, trying rules in order.
+    pub fn reply_for(&self, sticker: &StickerRef, keywords: &[String]) -> Option<&StickerRef> {
+        self.rules
+            .iter()
+            .find(|(matcher, _)| match matcher {
+                StickerMatch::Sticker(expected) => expected == sticker,
+                StickerMatch::Package(package_id) => *package_id == sticker.package_id,
+                StickerMatch::Keyword(keyword) => {
+                    keywords.iter().any(|k| k.to_lowercase() == *keyword)
+                }
+            })
+            .map(|(_, reply)| reply)
+    }
+
+    /// Picks the oracle's yes or no sticker for an answer, if configured.
+    pub fn oracle_reply(&self, yes: bool) -> Option<&StickerRef> {
+        self.oracle
+            .as_ref()
+            .map(|(yes_sticker, no_sticker)| if yes { yes_sticker } else { no_sticker })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn sticker(package_id: &str, sticker_id: &str) -> StickerRef {
+        StickerRef {
+            package_id: package_id.to_string(),
+            sticker_id: sticker_id.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_matches_rules_in_order() {
+        let env = HashMap::from([(
+            "DOLPHIN_STICKER_REPLIES".to_string(),
+            "446/1988=446/1989, 789/*=789/10855,Hello=11537/52002734,broken".to_string(),
+        )]);
+        let config = StickerConfig::from_env(&env);
+
+        assert_eq!(
+            config.reply_for(&sticker("446", "1988"), &[]),
+            Some(&sticker("446", "1989"))
+        );
+        assert_eq!(
+            config.reply_for(&sticker("789", "10856"), &[]),
+            Some(&sticker("789", "10855"))
+        );
+        assert_eq!(
+            config.reply_for(&sticker("1", "2"), &["hello".to_string()]),
+            Some(&sticker("11537", "52002734"))
+        );
+        assert_eq!(config.reply_for(&sticker("446", "1990"), &[]), None);
+        assert_eq!(config.oracle_reply(true), None);
+    }
+
+    #[test]
+    fn test_oracle_needs_both_stickers() {
+        let mut env = HashMap::from([("DOLPHIN_STICKER_YES".to_string(), "1/2".to_string())]);
+        assert_eq!(StickerConfig::from_env(&env).oracle_reply(true), None);
+
+        env.insert("DOLPHIN_STICKER_NO".to_string(), "1/3".to_string());
+        let config = StickerConfig::from_env(&env);
+        assert_eq!(config.oracle_reply(true), Some(&sticker("1", "2")));
+        assert_eq!(config.oracle_reply(false), Some(&sticker("1", "3")));
+    }
+}
@@ -1,10 +1,587 @@
-use serde::Serialize;
+use log::warn;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap};
 
-#[derive(Debug, Serialize)]
-pub struct ReplyMessage {
+#[derive(Debug, Deserialize)]
+pub struct WebhookRequest {
+    pub destination: String,
+    #[serde(deserialize_with = "deserialize_events")]
+    pub events: Vec<WebhookEvent>,
+}
+
+/// Parses events one by one, dropping any that don't fit the schema so a
+/// single odd event can't reject the whole batch.
+fn deserialize_events<'de, D>(deserializer: D) -> Result<Vec<WebhookEvent>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let raw_events = Vec::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(raw_events
+        .into_iter()
+        .filter_map(|raw| match serde_json::from_value(raw) {
+            Ok(event) => Some(event),
+            Err(e) => {
+                warn!("Dropping webhook event that failed to parse: {}", e);
+                None
+            }
+        })
+        .collect())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookEvent {
+    #[serde(rename = "webhookEventId")]
+    pub webhook_event_id: String,
+    #[serde(rename = "deliveryContext")]
+    pub delivery_context: DeliveryContext,
+    pub source: Source,
+    pub timestamp: i64,
+    pub mode: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+impl WebhookEvent {
+    pub fn reply_token(&self) -> Option<&str> {
+        self.kind.reply_token()
+    }
+
+    /// True when another channel is active in the chat, which leaves the bot
+    /// without a reply token.
+    pub fn is_standby(&self) -> bool {
+        self.mode == "standby"
+    }
+}
+
+/// The event-specific part of a webhook event, tagged by its `type`.
+#[derive(Debug, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum EventKind {
+    Message {
+        reply_token: Option<String>,
+        message: Message,
+    },
+    Follow {
+        reply_token: Option<String>,
+        follow: Option<Follow>,
+    },
+    Unfollow,
+    Join {
+        reply_token: Option<String>,
+    },
+    Leave,
+    MemberJoined {
+        reply_token: Option<String>,
+        joined: Members,
+    },
+    MemberLeft {
+        left: Members,
+    },
+    Postback {
+        reply_token: Option<String>,
+        postback: Postback,
+    },
+    Unsend {
+        unsend: Unsend,
+    },
+    VideoPlayComplete {
+        reply_token: Option<String>,
+        video_play_complete: VideoPlayComplete,
+    },
+    Beacon {
+        reply_token: Option<String>,
+        beacon: Beacon,
+    },
+    AccountLink {
+        reply_token: Option<String>,
+        link: AccountLink,
+    },
+    /// Any event type this bot doesn't know about.
+    #[serde(other)]
+    Unknown,
+}
+
+impl EventKind {
+    pub fn reply_token(&self) -> Option<&str> {
+        match self {
+            EventKind::Message { reply_token, .. }
+            | EventKind::Follow { reply_token, .. }
+            | EventKind::Join { reply_token }
+            | EventKind::MemberJoined { reply_token, .. }
+            | EventKind::Postback { reply_token, .. }
+            | EventKind::VideoPlayComplete { reply_token, .. }
+            | EventKind::Beacon { reply_token, .. }
+            | EventKind::AccountLink { reply_token, .. } => {
+                reply_token.as_deref().filter(|token| !token.is_empty())
+            }
+            EventKind::Unfollow
+            | EventKind::Leave
+            | EventKind::MemberLeft { .. }
+            | EventKind::Unsend { .. }
+            | EventKind::Unknown => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeliveryContext {
+    #[serde(rename = "isRedelivery")]
+    pub is_redelivery: bool,
+}
+
+/// Message content, tagged by its `type`.
+#[derive(Debug, Deserialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Message {
+    Text {
+        id: String,
+        text: String,
+        quote_token: Option<String>,
+        mention: Option<Mention>,
+    },
+    Image {
+        id: String,
+        quote_token: Option<String>,
+    },
+    Video {
+        id: String,
+        duration: Option<u64>,
+        quote_token: Option<String>,
+    },
+    Audio {
+        id: String,
+        duration: Option<u64>,
+    },
+    File {
+        id: String,
+        file_name: String,
+        file_size: u64,
+    },
+    Location {
+        id: String,
+        title: Option<String>,
+        address: Option<String>,
+        latitude: f64,
+        longitude: f64,
+    },
+    Sticker {
+        id: String,
+        package_id: String,
+        sticker_id: String,
+        #[serde(default)]
+        keywords: Vec<String>,
+        text: Option<String>,
+        quote_token: Option<String>,
+    },
+    /// Any message type this bot doesn't know about.
+    #[serde(other)]
+    Unknown,
+}
+
+/// The users mentioned in a text message.
+#[derive(Debug, Deserialize)]
+pub struct Mention {
+    pub mentionees: Vec<MentionSpan>,
+}
+
+/// Where a mention sits in the text, counted in UTF-16 code units.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MentionSpan {
+    pub index: usize,
+    pub length: usize,
+    /// `user` or `all`.
     #[serde(rename = "type")]
-    pub message_type: String,
-    pub text: String,
+    pub mention_type: String,
+    pub user_id: Option<String>,
+    /// True when the bot itself is mentioned.
+    #[serde(default)]
+    pub is_self: bool,
+}
+
+impl Mention {
+    pub fn mentions_self(&self) -> bool {
+        self.mentionees.iter().any(|mentionee| mentionee.is_self)
+    }
+
+    /// Returns `text` with the mentions of the bot cut out.
+    pub fn strip_self(&self, text: &str) -> String {
+        // Byte offset of every UTF-16 position that starts a character
+        let mut byte_offsets = HashMap::new();
+        let mut utf16_offset = 0;
+        for (byte_offset, c) in text.char_indices() {
+            byte_offsets.insert(utf16_offset, byte_offset);
+            utf16_offset += c.len_utf16();
+        }
+        byte_offsets.insert(utf16_offset, text.len());
+
+        let mut spans: Vec<(usize, usize)> = self
+            .mentionees
+            .iter()
+            .filter(|mentionee| mentionee.is_self)
+            .filter_map(|mentionee| {
+                let start = *byte_offsets.get(&mentionee.index)?;
+                let end = *byte_offsets.get(&(mentionee.index + mentionee.length))?;
+                Some((start, end))
+            })
+            .collect();
+        spans.sort();
+
+        let mut stripped = String::with_capacity(text.len());
+        let mut copied_to = 0;
+        for (start, end) in spans {
+            if start >= copied_to {
+                stripped.push_str(&text[copied_to..start]);
+                copied_to = end;
+            }
+        }
+        stripped.push_str(&text[copied_to..]);
+        stripped
+    }
+}
+
+impl Message {
+    /// The token for quoting this message, if it can be quoted.
+    pub fn quote_token(&self) -> Option<&str> {
+        match self {
+            Message::Text { quote_token, .. }
+            | Message::Image { quote_token, .. }
+            | Message::Video { quote_token, .. }
+            | Message::Sticker { quote_token, .. } => quote_token.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Follow {
+    #[serde(rename = "isUnblocked")]
+    pub is_unblocked: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Members {
+    pub members: Vec<Source>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Postback {
+    pub data: String,
+    pub params: Option<PostbackParams>,
+}
+
+/// What a datetime picker or rich menu switch action adds to its postback.
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PostbackParams {
+    /// `yyyy-MM-dd` from a date picker.
+    pub date: Option<String>,
+    /// `HH:mm` from a time picker.
+    pub time: Option<String>,
+    /// `yyyy-MM-ddTHH:mm` from a datetime picker.
+    pub datetime: Option<String>,
+    /// The rich menu alias a rich menu switch action switched to.
+    pub new_rich_menu_alias_id: Option<String>,
+    /// `SUCCESS`, `RICHMENU_ALIAS_ID_NOTFOUND`, `RICHMENU_NOTFOUND` or `FAILED`
+    /// for a rich menu switch action.
+    pub status: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Unsend {
+    #[serde(rename = "messageId")]
+    pub message_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct VideoPlayComplete {
+    #[serde(rename = "trackingId")]
+    pub tracking_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Beacon {
+    pub hwid: String,
+    #[serde(rename = "type")]
+    pub beacon_type: String,
+    pub dm: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AccountLink {
+    pub result: String,
+    pub nonce: String,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct Source {
+    #[serde(rename = "type")]
+    pub source_type: String,
+    #[serde(rename = "userId")]
+    pub user_id: Option<String>,
+    #[serde(rename = "groupId")]
+    pub group_id: Option<String>,
+    #[serde(rename = "roomId")]
+    pub room_id: Option<String>,
+}
+
+impl Source {
+    /// The conversation the event happened in.
+    pub fn conversation(&self) -> Option<Conversation<'_>> {
+        match (&self.group_id, &self.room_id, &self.user_id) {
+            (Some(group_id), _, _) => Some(Conversation::Group(group_id)),
+            (None, Some(room_id), _) => Some(Conversation::Room(room_id)),
+            (None, None, Some(user_id)) => Some(Conversation::User(user_id)),
+            (None, None, None) => None,
+        }
+    }
+
+    /// The ID to push to for answering in the same conversation.
+    pub fn chat_id(&self) -> Option<&str> {
+        self.conversation().map(|conversation| conversation.id())
+    }
+
+    /// The group or room ID, or `None` in a one-on-one chat.
+    pub fn group_chat_id(&self) -> Option<&str> {
+        self.conversation()
+            .and_then(|conversation| conversation.group_chat_id())
+    }
+}
+
+/// A chat the bot is in: a group, a multi-person room, or a one-on-one chat
+/// with a user. Groups and rooms behave the same throughout the bot.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Conversation<'s> {
+    Group(&'s str),
+    Room(&'s str),
+    User(&'s str),
+}
+
+impl<'s> Conversation<'s> {
+    pub fn id(&self) -> &'s str {
+        match self {
+            Conversation::Group(id) | Conversation::Room(id) | Conversation::User(id) => id,
+        }
+    }
+
+    /// The group or room ID, or `None` for a one-on-one chat.
+    pub fn group_chat_id(&self) -> Option<&'s str> {
+        match self {
+            Conversation::Group(id) | Conversation::Room(id) => Some(id),
+            Conversation::User(_) => None,
+        }
+    }
+}
+
+/// An outgoing message, tagged by its `type`. Any message can carry quick
+/// reply buttons, see [`ReplyMessage::with_quick_reply`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum ReplyMessage {
+    Text {
+        text: String,
+        /// Quotes the message with this `quoteToken`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        quote_token: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        quick_reply: Option<QuickReply>,
+    },
+    /// Text whose `{key}` placeholders are filled from `substitution`.
+    /// Literal braces in `text` must be doubled.
+    TextV2 {
+        text: String,
+        substitution: BTreeMap<String, Substitution>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        quick_reply: Option<QuickReply>,
+    },
+    Sticker {
+        package_id: String,
+        sticker_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        quick_reply: Option<QuickReply>,
+    },
+    /// A Flex Message; `alt_text` is what notifications and chat lists show.
+    Flex {
+        alt_text: String,
+        contents: serde_json::Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        quick_reply: Option<QuickReply>,
+    },
+}
+
+/// The longest `altText` LINE accepts.
+const MAX_ALT_TEXT_CHARS: usize = 400;
+
+impl ReplyMessage {
+    pub fn text(text: &str) -> Self {
+        ReplyMessage::Text {
+            text: text.to_string(),
+            quote_token: None,
+            quick_reply: None,
+        }
+    }
+
+    /// A text message that quotes the message with `quote_token`.
+    pub fn quoted_text(text: &str, quote_token: &str) -> Self {
+        ReplyMessage::Text {
+            text: text.to_string(),
+            quote_token: Some(quote_token.to_string()),
+            quick_reply: None,
+        }
+    }
+
+    pub fn text_v2(text: &str, substitution: BTreeMap<String, Substitution>) -> Self {
+        ReplyMessage::TextV2 {
+            text: text.to_string(),
+            substitution,
+            quick_reply: None,
+        }
+    }
+
+    pub fn sticker(package_id: &str, sticker_id: &str) -> Self {
+        ReplyMessage::Sticker {
+            package_id: package_id.to_string(),
+            sticker_id: sticker_id.to_string(),
+            quick_reply: None,
+        }
+    }
+
+    /// A Flex Message. LINE requires an `altText`, so it is cut to fit and
+    /// an empty one becomes "🐬".
+    pub fn flex(alt_text: &str, contents: serde_json::Value) -> Self {
+        let alt_text: String = alt_text.trim().chars().take(MAX_ALT_TEXT_CHARS).collect();
+        ReplyMessage::Flex {
+            alt_text: if alt_text.is_empty() {
+                "🐬".to_string()
+            } else {
+                alt_text
+            },
+            contents,
+            quick_reply: None,
+        }
+    }
+
+    /// Attaches quick reply buttons, builder style. LINE shows them under
+    /// the last message of a reply or push.
+    pub fn with_quick_reply(mut self, buttons: QuickReply) -> Self {
+        match &mut self {
+            ReplyMessage::Text { quick_reply, .. }
+            | ReplyMessage::TextV2 { quick_reply, .. }
+            | ReplyMessage::Sticker { quick_reply, .. }
+            | ReplyMessage::Flex { quick_reply, .. } => *quick_reply = Some(buttons),
+        }
+        self
+    }
+}
+
+/// Buttons shown under a message until the user taps one or sends
+/// something else.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QuickReply {
+    pub items: Vec<QuickReplyItem>,
+}
+
+impl QuickReply {
+    pub fn new(actions: impl IntoIterator<Item = Action>) -> Self {
+        QuickReply {
+            items: actions
+                .into_iter()
+                .map(|action| QuickReplyItem::Action { action })
+                .collect(),
+        }
+    }
+}
+
+/// A quick reply button, tagged by its `type`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum QuickReplyItem {
+    Action { action: Action },
+}
+
+/// What tapping a button does, tagged by its `type`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Action {
+    /// Sends `text` as the user.
+    Message { label: String, text: String },
+    /// Sends a postback event with `data`, showing `display_text` as the
+    /// user's message when set.
+    Postback {
+        label: String,
+        data: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        display_text: Option<String>,
+    },
+}
+
+impl Action {
+    pub fn message(label: &str, text: &str) -> Self {
+        Action::Message {
+            label: label.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    pub fn postback(label: &str, data: &str, display_text: Option<&str>) -> Self {
+        Action::Postback {
+            label: label.to_string(),
+            data: data.to_string(),
+            display_text: display_text.map(str::to_string),
+        }
+    }
+}
+
+/// A `textV2` placeholder value, tagged by its `type`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Substitution {
+    Mention { mentionee: Mentionee },
+}
+
+impl Substitution {
+    pub fn mention_user(user_id: &str) -> Self {
+        Substitution::Mention {
+            mentionee: Mentionee::User {
+                user_id: user_id.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(
+    tag = "type",
+    rename_all = "camelCase",
+    rename_all_fields = "camelCase"
+)]
+pub enum Mentionee {
+    User { user_id: String },
+}
+
+/// A user's profile, as a friend of the bot or as a member of a chat.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub display_name: String,
+    pub user_id: String,
+    pub picture_url: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
@@ -13,3 +590,207 @@ pub struct ReplyRequest {
     pub reply_token: String,
     pub messages: Vec<ReplyMessage>,
 }
+
+#[derive(Debug, Serialize)]
+pub struct PushRequest {
+    pub to: String,
+    pub messages: Vec<ReplyMessage>,
+}
+
+/// Body of a Chat Control acquire call; with `expired` the chat returns to the
+/// primary channel after `ttl` seconds.
+#[derive(Debug, Serialize)]
+pub struct AcquireControlRequest {
+    pub expired: bool,
+    pub ttl: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: &str, extra: &str) -> String {
+        format!(
+            r#"{{"type":"{}",{}"webhookEventId":"01H{}","deliveryContext":{{"isRedelivery":false}},"timestamp":1700000000000,"source":{{"type":"user","userId":"U1"}},"mode":"active"}}"#,
+            kind, extra, kind
+        )
+    }
+
+    #[test]
+    fn test_parses_every_event_type() {
+        let events = [
+            event(
+                "message",
+                r#""replyToken":"r","message":{"type":"text","id":"1","text":"hi","quoteToken":"q"},"#,
+            ),
+            event(
+                "message",
+                r#""replyToken":"r","message":{"type":"sticker","id":"2","packageId":"446","stickerId":"1988","stickerResourceType":"STATIC","keywords":["Hi"]},"#,
+            ),
+            event(
+                "message",
+                r#""replyToken":"r","message":{"type":"location","id":"3","latitude":35.6,"longitude":139.7},"#,
+            ),
+            event(
+                "message",
+                r#""replyToken":"r","message":{"type":"hologram","id":"4"},"#,
+            ),
+            event(
+                "follow",
+                r#""replyToken":"r","follow":{"isUnblocked":false},"#,
+            ),
+            event("unfollow", ""),
+            event("join", r#""replyToken":"r","#),
+            event("leave", ""),
+            event(
+                "memberJoined",
+                r#""replyToken":"r","joined":{"members":[{"type":"user","userId":"U2"}]},"#,
+            ),
+            event(
+                "memberLeft",
+                r#""left":{"members":[{"type":"user","userId":"U2"}]},"#,
+            ),
+            event(
+                "postback",
+                r#""replyToken":"r","postback":{"data":"action=vote","params":{"date":"2024-01-01"}},"#,
+            ),
+            event("unsend", r#""unsend":{"messageId":"1"},"#),
+            event(
+                "videoPlayComplete",
+                r#""replyToken":"r","videoPlayComplete":{"trackingId":"t"},"#,
+            ),
+            event(
+                "beacon",
+                r#""replyToken":"r","beacon":{"hwid":"d41d8cd98f","type":"enter"},"#,
+            ),
+            event(
+                "accountLink",
+                r#""replyToken":"r","link":{"result":"ok","nonce":"n"},"#,
+            ),
+            event("membership", r#""membership":{"type":"joined"},"#),
+            event(
+                "postback",
+                r#""replyToken":"r","postback":{"data":"menu=2","params":{"newRichMenuAliasId":"menu-2","status":"SUCCESS"}},"#,
+            ),
+        ];
+        let body = format!(
+            r#"{{"destination":"Ubot","events":[{}]}}"#,
+            events.join(",")
+        );
+
+        let request: WebhookRequest = serde_json::from_str(&body).unwrap();
+        let kinds: Vec<&EventKind> = request.events.iter().map(|e| &e.kind).collect();
+
+        assert_eq!(kinds.len(), events.len());
+        assert!(matches!(
+            kinds[0],
+            EventKind::Message { message: Message::Text { text, .. }, .. } if text == "hi"
+        ));
+        assert!(matches!(
+            kinds[1],
+            EventKind::Message { message: Message::Sticker { sticker_id, .. }, .. } if sticker_id == "1988"
+        ));
+        assert!(matches!(
+            kinds[2],
+            EventKind::Message {
+                message: Message::Location { .. },
+                ..
+            }
+        ));
+        assert!(matches!(
+            kinds[3],
+            EventKind::Message {
+                message: Message::Unknown,
+                ..
+            }
+        ));
+        assert!(matches!(kinds[4], EventKind::Follow { .. }));
+        assert!(matches!(kinds[5], EventKind::Unfollow));
+        assert!(matches!(kinds[6], EventKind::Join { .. }));
+        assert!(matches!(kinds[7], EventKind::Leave));
+        assert!(matches!(kinds[8], EventKind::MemberJoined { .. }));
+        assert!(matches!(kinds[9], EventKind::MemberLeft { .. }));
+        assert!(matches!(
+            kinds[10],
+            EventKind::Postback { postback, .. } if postback.data == "action=vote"
+                && postback.params.as_ref().and_then(|p| p.date.as_deref()) == Some("2024-01-01")
+        ));
+        assert!(matches!(kinds[11], EventKind::Unsend { unsend } if unsend.message_id == "1"));
+        assert!(matches!(kinds[12], EventKind::VideoPlayComplete { .. }));
+        assert!(matches!(kinds[13], EventKind::Beacon { .. }));
+        assert!(matches!(kinds[14], EventKind::AccountLink { .. }));
+        assert!(matches!(kinds[15], EventKind::Unknown));
+        assert!(matches!(
+            kinds[16],
+            EventKind::Postback { postback, .. } if postback.params == Some(PostbackParams {
+                new_rich_menu_alias_id: Some("menu-2".to_string()),
+                status: Some("SUCCESS".to_string()),
+                ..Default::default()
+            })
+        ));
+        assert_eq!(request.events[0].reply_token(), Some("r"));
+        assert_eq!(request.events[5].reply_token(), None);
+    }
+
+    #[test]
+    fn test_serializes_reply_messages() {
+        let message = ReplyMessage::text_v2(
+            "Welcome {user1}!",
+            BTreeMap::from([("user1".to_string(), Substitution::mention_user("U1"))]),
+        );
+
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            serde_json::json!({
+                "type": "textV2",
+                "text": "Welcome {user1}!",
+                "substitution": {
+                    "user1": {"type": "mention", "mentionee": {"type": "user", "userId": "U1"}}
+                }
+            })
+        );
+        assert_eq!(
+            serde_json::to_value(ReplyMessage::quoted_text("yes", "q1")).unwrap(),
+            serde_json::json!({"type": "text", "text": "yes", "quoteToken": "q1"})
+        );
+        assert_eq!(
+            serde_json::to_value(ReplyMessage::sticker("446", "1988")).unwrap(),
+            serde_json::json!({"type": "sticker", "packageId": "446", "stickerId": "1988"})
+        );
+    }
+
+    #[test]
+    fn test_strips_self_mentions_by_utf16_position() {
+        let message: Message = serde_json::from_str(
+            r#"{"type":"text","id":"1","text":"🐬 @Dolphin Bot will it rain? @Ann","mention":{"mentionees":[
+                {"index":3,"length":12,"type":"user","userId":"Ubot","isSelf":true},
+                {"index":30,"length":4,"type":"user","userId":"U2"}]}}"#,
+        )
+        .unwrap();
+        let Message::Text {
+            text,
+            mention: Some(mention),
+            ..
+        } = message
+        else {
+            panic!("expected a text message with mentions");
+        };
+
+        assert!(mention.mentions_self());
+        assert_eq!(mention.strip_self(&text), "🐬  will it rain? @Ann");
+    }
+
+    #[test]
+    fn test_malformed_event_does_not_reject_the_batch() {
+        let body = format!(
+            r#"{{"destination":"Ubot","events":[{},{}]}}"#,
+            event("postback", r#""replyToken":"r","#),
+            event("join", r#""replyToken":"r","#)
+        );
+
+        let request: WebhookRequest = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(request.events.len(), 1);
+        assert!(matches!(request.events[0].kind, EventKind::Join { .. }));
+    }
+}
@@ -0,0 +1,250 @@
+//! Replays signed webhook fixtures as HTTP requests to the server's
+//! [`router`], against an in-memory state store and a recording LINE client.
+//!
+//! Each request goes through routing, signature verification and the
+//! server's `EventQueue`; a step ends once the queue has handled all its
+//! events, so its LINE calls can be checked. The Workers entry point is not
+//! covered.
+//!
+//! Each `tests/fixtures/webhooks/*.jsonl` file is one scenario with fresh
+//! state. Every line is a step:
+//!
+//! ```json
+//! {"step": "...", "webhook": {...}, "channel": "optional", "signature": "optional", "stale_reply_token": false, "env": {}, "expect": {"status": 200, "calls": [...]}}
+//! ```
+//!
+//! The webhook is posted to `/webhook/{channel}`, or to `/webhook` when
+//! `channel` is missing, and signed with that channel's
+//! secret unless `signature` is given. `env` adds configuration variables
+//! for that step only. With `stale_reply_token` every reply of the step
+//! fails the way an expired reply token does. `calls` lists the LINE API
+//! calls the step must make, in order, as
+//! `{"reply": {"replyToken": ..., "messages": [...]}}`,
+//! `{"push": {"to": ..., "messages": [...]}}`,
+//! `{"acquireChatControl": chatId}` or `{"releaseChatControl": chatId}`.
+//! Expected objects only need to contain the fields under test.
+
+use axum::body::Body;
+use axum::http::Request;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::ServiceExt;
+
+use line_dolphin_worker::channel::{Channel, Channels};
+use line_dolphin_worker::dolphin::{sign_body, Secrets};
+use line_dolphin_worker::line::{LineCall, LineClient, RecordingLineClient};
+use line_dolphin_worker::server::{router, AppContext, AppState, EventQueue};
+use line_dolphin_worker::state::MemoryStore;
+use line_dolphin_worker::types::{Conversation, Profile, ReplyMessage};
+use line_dolphin_worker::Result;
+
+const CHANNEL_SECRET: &str = "fixture_channel_secret";
+
+#[derive(Debug, Deserialize)]
+struct Step {
+    step: String,
+    webhook: Value,
+    channel: Option<String>,
+    signature: Option<String>,
+    #[serde(default)]
+    stale_reply_token: bool,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    expect: Expect,
+}
+
+#[derive(Debug, Deserialize)]
+struct Expect {
+    #[serde(default = "default_status")]
+    status: u16,
+    #[serde(default)]
+    calls: Vec<Value>,
+}
+
+fn default_status() -> u16 {
+    200
+}
+
+fn fixture_env() -> HashMap<String, String> {
+    HashMap::from([
+        (
+            "LINE_CHANNEL_SECRET".to_string(),
+            CHANNEL_SECRET.to_string(),
+        ),
+        (
+            "DOLPHIN_USER_TO_GROUP1".to_string(),
+            "U00000000000000000000000000000a11:C00000000000000000000000000001234".to_string(),
+        ),
+        (
+            "DOLPHIN_USER_TO_GROUP2".to_string(),
+            "U00000000000000000000000000000b22:C00000000000000000000000000005678".to_string(),
+        ),
+    ])
+}
+
+/// One recorder behind every channel's LINE client, so a scenario's calls
+/// are checked in the order they were made.
+#[derive(Clone)]
+struct SharedLine(Arc<RecordingLineClient>);
+
+impl LineClient for SharedLine {
+    async fn reply(&self, reply_token: &str, messages: Vec<ReplyMessage>) -> Result<()> {
+        self.0.reply(reply_token, messages).await
+    }
+
+    async fn push(&self, to: &str, messages: Vec<ReplyMessage>) -> Result<()> {
+        self.0.push(to, messages).await
+    }
+
+    async fn acquire_chat_control(&self, chat_id: &str, ttl: Duration) -> Result<()> {
+        self.0.acquire_chat_control(chat_id, ttl).await
+    }
+
+    async fn release_chat_control(&self, chat_id: &str) -> Result<()> {
+        self.0.release_chat_control(chat_id).await
+    }
+
+    async fn get_profile(&self, user_id: &str, chat: Conversation<'_>) -> Result<Profile> {
+        self.0.get_profile(user_id, chat).await
+    }
+}
+
+type FixtureState = AppState<HashMap<String, String>, MemoryStore, SharedLine>;
+
+/// Posts a webhook to the router and waits until the queue has handled its
+/// events. Returns the response status and the state, for the next step.
+async fn post_webhook(
+    state: FixtureState,
+    channel: Option<&str>,
+    signature: &str,
+    body: Vec<u8>,
+) -> (u16, FixtureState) {
+    let state = Arc::new(state);
+    let (queue, workers) = EventQueue::start(1, {
+        let state = state.clone();
+        move |channel: Channel, event| {
+            let state = state.clone();
+            async move { state.handle_event(&channel, &event).await }
+        }
+    });
+    let app = router(AppContext {
+        state: state.clone(),
+        queue,
+    });
+
+    let uri = match channel {
+        Some(channel) => format!("/webhook/{}", channel),
+        None => "/webhook".to_string(),
+    };
+    let request = Request::post(uri)
+        .header("content-type", "application/json")
+        .header("x-line-signature", signature)
+        .body(Body::from(body))
+        .unwrap();
+    // The router owns the queue, so the workers finish once it is dropped
+    let response = app.oneshot(request).await.unwrap();
+    workers.join().await;
+
+    let state = Arc::try_unwrap(state).unwrap_or_else(|_| panic!("app state still in use"));
+    (response.status().as_u16(), state)
+}
+
+fn call_to_json(call: &LineCall) -> Value {
+    match call {
+        LineCall::Reply {
+            reply_token,
+            messages,
+        } => json!({"reply": {"replyToken": reply_token, "messages": messages}}),
+        LineCall::Push { to, messages } => json!({"push": {"to": to, "messages": messages}}),
+        LineCall::AcquireChatControl { chat_id } => json!({"acquireChatControl": chat_id}),
+        LineCall::ReleaseChatControl { chat_id } => json!({"releaseChatControl": chat_id}),
+    }
+}
+
+/// Returns true when every field in `expected` is present and equal in `actual`.
+fn matches(expected: &Value, actual: &Value) -> bool {
+    match (expected, actual) {
+        (Value::Object(expected), Value::Object(actual)) => expected
+            .iter()
+            .all(|(key, value)| actual.get(key).is_some_and(|a| matches(value, a))),
+        (Value::Array(expected), Value::Array(actual)) => {
+            expected.len() == actual.len()
+                && expected.iter().zip(actual).all(|(e, a)| matches(e, a))
+        }
+        _ => expected == actual,
+    }
+}
+
+async fn replay(path: &Path) {
+    let line = SharedLine(Arc::new(RecordingLineClient::new()));
+    let mut state = AppState {
+        env: HashMap::new(),
+        store: MemoryStore::new(),
+        channels: Channels::default(),
+        lines: HashMap::new(),
+    };
+
+    let content = fs::read_to_string(path).unwrap();
+    for (index, raw) in content.lines().enumerate() {
+        if raw.trim().is_empty() {
+            continue;
+        }
+        let step: Step = serde_json::from_str(raw)
+            .unwrap_or_else(|e| panic!("{}:{}: bad fixture: {}", path.display(), index + 1, e));
+        let context = format!("{}:{} ({})", path.display(), index + 1, step.step);
+
+        let body = serde_json::to_vec(&step.webhook).unwrap();
+        line.0.reject_replies(step.stale_reply_token);
+        let mut env = fixture_env();
+        env.extend(step.env);
+        let channels = Channels::from_env(&env);
+        let signature = step.signature.unwrap_or_else(|| {
+            let secret = channels
+                .route(step.channel.as_deref(), &body)
+                .ok()
+                .and_then(|channel| channel.env(&env).get("LINE_CHANNEL_SECRET"));
+            sign_body(&body, &secret.unwrap_or_default())
+        });
+        state.lines = std::iter::once(Channel::default())
+            .chain(channels.iter().cloned())
+            .map(|channel| (channel.name, line.clone()))
+            .collect();
+        state.env = env;
+        state.channels = channels;
+
+        let status;
+        (status, state) = post_webhook(state, step.channel.as_deref(), &signature, body).await;
+        assert_eq!(status, step.expect.status, "{}: status", context);
+
+        let actual: Vec<Value> = line.0.take_calls().iter().map(call_to_json).collect();
+        let expected = Value::Array(step.expect.calls);
+        assert!(
+            matches(&expected, &Value::Array(actual.clone())),
+            "{}: calls differ\nexpected: {:#}\nactual:   {:#}",
+            context,
+            expected,
+            Value::Array(actual)
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_webhook_fixtures() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/webhooks");
+    let mut paths: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "jsonl"))
+        .collect();
+    paths.sort();
+    assert!(!paths.is_empty(), "no fixtures in {}", dir.display());
+
+    for path in paths {
+        replay(&path).await;
+    }
+}